@@ -0,0 +1,235 @@
+use core::mem::size_of;
+use spin::Once;
+use x86_64::PhysAddr;
+
+use crate::memory;
+
+// tables are read through the physical memory mapping, they live in memory the bootloader maps
+fn read<T: Copy>(address: PhysAddr) -> T {
+    let virtual_address = memory::physical_memory_offset() + address.as_u64();
+    unsafe {virtual_address.as_ptr::<T>().read_unaligned()}
+}
+
+fn checksum_ok(address: PhysAddr, length: usize) -> bool {
+    (0..length as u64).fold(0u8, |sum, offset| sum.wrapping_add(read::<u8>(address + offset))) == 0
+}
+
+// header in front of every system description table
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // the fields below exist from revision 2 on
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+// the first 20 bytes are covered by the checksum of revision 0
+const RSDP_V1_LENGTH: usize = 20;
+
+// root table, the entries are 32 bit addresses in the RSDT and 64 bit addresses in the XSDT
+#[derive(Debug, Clone, Copy)]
+struct RootTable {
+    address: PhysAddr,
+    entry_size: u64,
+}
+
+static ROOT: Once<Option<RootTable>> = Once::new();
+
+// the RSDP is on a 16 byte boundary in the first KiB of the EBDA or in the BIOS area
+fn find_rsdp() -> Option<PhysAddr> {
+    let ebda = u64::from(read::<u16>(PhysAddr::new(0x40e))) << 4;
+    let candidates = (ebda..ebda + 1024).step_by(16).chain((0xe_0000..0x10_0000).step_by(16));
+    candidates.map(PhysAddr::new).find(|&address| {
+        read::<[u8; 8]>(address) == *RSDP_SIGNATURE && checksum_ok(address, RSDP_V1_LENGTH)
+    })
+}
+
+fn root_table() -> Option<RootTable> {
+    *ROOT.call_once(|| {
+        let address = find_rsdp()?;
+        let rsdp = read::<Rsdp>(address);
+        let root = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 && checksum_ok(address, rsdp.length as usize) {
+            RootTable { address: PhysAddr::new(rsdp.xsdt_address), entry_size: 8 }
+        } else {
+            RootTable { address: PhysAddr::new(u64::from(rsdp.rsdt_address)), entry_size: 4 }
+        };
+        let header = read::<SdtHeader>(root.address);
+        checksum_ok(root.address, header.length as usize).then_some(root)
+    })
+}
+
+// physical address of the first table with the signature whose checksum is valid
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    let root = root_table()?;
+    let header = read::<SdtHeader>(root.address);
+    let entries = (u64::from(header.length) - size_of::<SdtHeader>() as u64) / root.entry_size;
+    (0..entries)
+        .map(|index| {
+            let entry = root.address + size_of::<SdtHeader>() as u64 + index * root.entry_size;
+            if root.entry_size == 8 { read::<u64>(entry) } else { u64::from(read::<u32>(entry)) }
+        })
+        .map(PhysAddr::new)
+        .find(|&table| {
+            let header = read::<SdtHeader>(table);
+            header.signature == *signature && checksum_ok(table, header.length as usize)
+        })
+}
+
+pub const MAX_IO_APICS: usize = 4;
+pub const ISA_IRQS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub address: PhysAddr,
+    // first global system interrupt handled by the IO-APIC
+    pub gsi_base: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+// how an ISA IRQ is connected to the IO-APICs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IsaRoute {
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+// what the kernel needs from the multiple APIC description table
+#[derive(Debug, Clone, Copy)]
+pub struct Madt {
+    pub local_apic: PhysAddr,
+    // the 8259 PICs are installed as well and have to be masked
+    pub has_legacy_pics: bool,
+    pub io_apics: [Option<IoApicEntry>; MAX_IO_APICS],
+    // ISA IRQs are identity mapped to GSIs unless an interrupt source override says otherwise
+    pub isa_routes: [IsaRoute; ISA_IRQS],
+}
+
+impl Madt {
+    pub fn io_apics(&self) -> impl Iterator<Item = &IoApicEntry> {
+        self.io_apics.iter().flatten()
+    }
+}
+
+const MADT_LOCAL_APIC_OFFSET: u64 = 36;
+const MADT_FLAGS_OFFSET: u64 = 40;
+const MADT_ENTRIES_OFFSET: u64 = 44;
+
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+pub fn madt() -> Option<Madt> {
+    let table = find_table(b"APIC")?;
+    let length = u64::from(read::<SdtHeader>(table).length);
+
+    let mut madt = Madt {
+        local_apic: PhysAddr::new(u64::from(read::<u32>(table + MADT_LOCAL_APIC_OFFSET))),
+        has_legacy_pics: read::<u32>(table + MADT_FLAGS_OFFSET) & 1 != 0,
+        io_apics: [None; MAX_IO_APICS],
+        isa_routes: core::array::from_fn(|irq| IsaRoute {
+            gsi: irq as u32,
+            polarity: Polarity::ActiveHigh,
+            trigger: TriggerMode::Edge,
+        }),
+    };
+
+    let mut offset = MADT_ENTRIES_OFFSET;
+    while offset + 2 <= length {
+        let entry = table + offset;
+        let (kind, entry_length) = (read::<u8>(entry), u64::from(read::<u8>(entry + 1u64)));
+        if entry_length < 2 {
+            break;
+        }
+        match kind {
+            ENTRY_IO_APIC => {
+                if let Some(slot) = madt.io_apics.iter_mut().find(|slot| slot.is_none()) {
+                    *slot = Some(IoApicEntry {
+                        id: read::<u8>(entry + 2u64),
+                        address: PhysAddr::new(u64::from(read::<u32>(entry + 4u64))),
+                        gsi_base: read::<u32>(entry + 8u64),
+                    });
+                }
+            }
+            ENTRY_INTERRUPT_SOURCE_OVERRIDE => {
+                let (bus, source) = (read::<u8>(entry + 2u64), read::<u8>(entry + 3u64));
+                let flags = read::<u16>(entry + 8u64);
+                if bus == 0 && (source as usize) < ISA_IRQS {
+                    madt.isa_routes[source as usize] = IsaRoute {
+                        gsi: read::<u32>(entry + 4u64),
+                        // 0b00 conforms to the bus, ISA is active high and edge triggered
+                        polarity: if flags & 0b11 == 0b11 { Polarity::ActiveLow } else { Polarity::ActiveHigh },
+                        trigger: if (flags >> 2) & 0b11 == 0b11 { TriggerMode::Level } else { TriggerMode::Edge },
+                    };
+                }
+            }
+            ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE => {
+                madt.local_apic = PhysAddr::new(read::<u64>(entry + 4u64));
+            }
+            _ => {}
+        }
+        offset += entry_length;
+    }
+    Some(madt)
+}
+
+// what the kernel needs from the HPET description table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HpetInfo {
+    pub address: PhysAddr,
+    pub number: u8,
+    // smallest period in counter ticks a periodic timer may be programmed with
+    pub minimum_tick: u16,
+}
+
+// the base address is a generic address structure, the HPET is always in memory space
+const HPET_ADDRESS_OFFSET: u64 = 44;
+const HPET_NUMBER_OFFSET: u64 = 52;
+const HPET_MINIMUM_TICK_OFFSET: u64 = 53;
+const ADDRESS_SPACE_MEMORY: u8 = 0;
+
+pub fn hpet() -> Option<HpetInfo> {
+    let table = find_table(b"HPET")?;
+    if read::<u8>(table + HPET_ADDRESS_OFFSET) != ADDRESS_SPACE_MEMORY {
+        return None;
+    }
+    Some(HpetInfo {
+        address: PhysAddr::new(read::<u64>(table + HPET_ADDRESS_OFFSET + 4u64)),
+        number: read::<u8>(table + HPET_NUMBER_OFFSET),
+        minimum_tick: read::<u16>(table + HPET_MINIMUM_TICK_OFFSET),
+    })
+}
@@ -1,64 +1,322 @@
 use x86_64::{
     VirtAddr,
-    structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB
-    }
+    structures::paging::{OffsetPageTable, PageTableFlags},
 };
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::memory::{self, bitmap::BitmapFrameAllocator, vmm::{self, RegionKind, VmmError}};
+use stats::{AllocationCounters, FreeStats, HeapStats};
+
+const PAGE_SIZE: usize = 4096;
+
+pub mod bump;
+pub mod linked_list;
+pub mod fixed_size_block;
+pub mod stats;
+pub mod slab;
+pub mod debug;
+pub mod leak;
+pub mod table;
 
-// // Linked List Allocator using the linked list allocator crate
 use linked_list_allocator::LockedHeap;
+
+// the global allocator is selected with one of the `alloc-*` cargo features
+// with the `alloc-debug` feature, the selected allocator is wrapped in a `debug::DebugAllocator`
+
+#[cfg(feature = "alloc-debug")]
+type Checked<A> = debug::DebugAllocator<A>;
+#[cfg(feature = "alloc-debug")]
+const fn checked<A>(inner: A) -> Checked<A> {
+    debug::DebugAllocator::new(inner)
+}
+
+#[cfg(not(feature = "alloc-debug"))]
+type Checked<A> = A;
+#[cfg(not(feature = "alloc-debug"))]
+const fn checked<A>(inner: A) -> Checked<A> {
+    inner
+}
+
+// Linked List Allocator using the linked list allocator crate
+#[cfg(feature = "alloc-crate")]
 #[global_allocator]
-static ALLOCATOR: LockedHeap= LockedHeap::empty();
-
-
-// // Bump (Stack) Allocator using the custom bump allocator implementation
-// pub mod bump;
-// use bump::BumpAllocator;
-// #[global_allocator]
-// static ALLOCATOR: Locked<BumpAllocator> =  Locked::new(BumpAllocator::new());
-
-// // Linked List Allocator using the custom linked list allocator implementation
-// pub mod linked_list;
-// use linked_list::LinkedListAllocator;
-// #[global_allocator]
-// static ALLOCATOR: Locked<LinkedListAllocator> =  Locked::new(LinkedListAllocator::new());
-
-// // Fixed Block Size Allocator using the custom fixed block size allocator implementation
-// pub mod fixed_size_block;
-// use fixed_size_block::FixedSizeBlockAllocator;
-// #[global_allocator]
-// static ALLOCATOR: Locked<FixedSizeBlockAllocator> =  Locked::new(FixedSizeBlockAllocator::new());
-
-pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 K bits
-
-pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-    ) -> Result<(), MapToError<Size4KiB>> {
-        let page_range = {
-            let heap_start = VirtAddr::new(HEAP_START as u64);
-            let heap_end = heap_start + HEAP_SIZE -1u64;
-            let heap_start_page = Page::containing_address(heap_start);
-            let heap_end_page = Page::containing_address(heap_end);
-            Page::range_inclusive(heap_start_page, heap_end_page)
-        };
+static ALLOCATOR: GrowableHeap<Checked<LockedHeap>> = GrowableHeap::new(checked(LockedHeap::empty()));
 
-        for page in page_range {
-            let frame = frame_allocator
-                        .allocate_frame()
-                        .ok_or(MapToError::FrameAllocationFailed)?;
-            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+// Bump (Stack) Allocator using the custom bump allocator implementation
+#[cfg(feature = "alloc-bump")]
+#[global_allocator]
+static ALLOCATOR: GrowableHeap<Checked<Locked<bump::BumpAllocator>>> =
+    GrowableHeap::new(checked(Locked::new(bump::BumpAllocator::new())));
+
+// Linked List Allocator using the custom linked list allocator implementation
+#[cfg(feature = "alloc-linked-list")]
+#[global_allocator]
+static ALLOCATOR: GrowableHeap<Checked<Locked<linked_list::LinkedListAllocator>>> =
+    GrowableHeap::new(checked(Locked::new(linked_list::LinkedListAllocator::new())));
+
+// Fixed Block Size Allocator using the custom fixed block size allocator implementation
+#[cfg(feature = "alloc-fixed-block")]
+#[global_allocator]
+static ALLOCATOR: GrowableHeap<Checked<Locked<fixed_size_block::FixedSizeBlockAllocator>>> =
+    GrowableHeap::new(checked(Locked::new(fixed_size_block::FixedSizeBlockAllocator::new())));
 
-            unsafe {mapper.map_to(page, frame, flags, frame_allocator)?.flush()};
+#[cfg(not(any(
+    feature = "alloc-crate",
+    feature = "alloc-bump",
+    feature = "alloc-linked-list",
+    feature = "alloc-fixed-block",
+)))]
+compile_error!("no heap allocator selected, enable one of the `alloc-*` features");
+
+#[cfg(any(
+    all(feature = "alloc-crate", feature = "alloc-bump"),
+    all(feature = "alloc-crate", feature = "alloc-linked-list"),
+    all(feature = "alloc-crate", feature = "alloc-fixed-block"),
+    all(feature = "alloc-bump", feature = "alloc-linked-list"),
+    all(feature = "alloc-bump", feature = "alloc-fixed-block"),
+    all(feature = "alloc-linked-list", feature = "alloc-fixed-block"),
+))]
+compile_error!("only one heap allocator can be selected, use `--no-default-features` with the `alloc-*` feature");
+
+// common interface used by `init_heap` and `GrowableHeap`, implemented by every allocator above
+pub trait HeapAllocator {
+    /// Hands the memory region `heap_start..heap_start + heap_size` to the allocator.
+    ///
+    /// # Safety
+    /// The region must be mapped, unused and valid for the lifetime of the kernel.
+    /// Must be called only once.
+    unsafe fn init(&self, heap_start: usize, heap_size: usize);
+
+    /// Adds `by` bytes directly after the current end of the heap.
+    ///
+    /// # Safety
+    /// The added memory must be mapped and unused.
+    unsafe fn extend(&self, by: usize);
+
+    // free memory as seen by the allocator, the allocation counters are kept by `GrowableHeap`
+    fn free_stats(&self) -> FreeStats;
+}
+
+impl HeapAllocator for LockedHeap {
+    unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+        self.lock().init(heap_start, heap_size);
+    }
+
+    unsafe fn extend(&self, by: usize) {
+        self.lock().extend(by);
+    }
+
+    // the hole list of the crate is private, so the largest free block is not reported
+    fn free_stats(&self) -> FreeStats {
+        FreeStats {
+            bytes_free: self.lock().free(),
+            largest_free_block: None,
+            size_classes: None,
         }
+    }
+}
+
+pub const HEAP_SIZE: usize = 100 * 1024; // 100 K bits, initial size of the heap
+pub const HEAP_MAX_SIZE: usize = 32 * 1024 * 1024; // default limit for growing the heap
+// virtual address space reserved for the heap, the limit can be raised up to this size
+pub const HEAP_RESERVED_SIZE: usize = 1024 * 1024 * 1024;
+
+// the heap grows by at least this many bytes, so small allocations don't map single pages
+const HEAP_GROWTH_STEP: usize = 64 * 1024;
+
+// start of the heap region, the vmm picks it in its dynamic window
+static HEAP_START: spin::Once<usize> = spin::Once::new();
+// current end of the mapped heap, only changed while the lock is held
+static HEAP_END: spin::Mutex<usize> = spin::Mutex::new(0);
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
 
-        unsafe {ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE)};
-        Ok(())
+// maps the initial heap, the mapper and frame allocator are taken from `memory::init_kernel_memory`
+pub fn init_heap() -> Result<(), VmmError> {
+    let mut heap_end = HEAP_END.lock();
+    assert!(HEAP_START.r#try().is_none(), "the heap is already initialized");
+    let region = vmm::reserve("heap", RegionKind::Heap, HEAP_RESERVED_SIZE as u64)?;
+    let heap_start = region.start.as_u64() as usize;
+    let mapped = memory::with_kernel_memory(|mapper, frame_allocator| {
+        map_heap_pages(heap_start, HEAP_SIZE, mapper, frame_allocator)
+    });
+    if let Err(error) = mapped {
+        let _ = vmm::release(region.start);
+        return Err(error);
+    }
+    HEAP_START.call_once(|| heap_start);
+    *heap_end = heap_start + HEAP_SIZE;
+
+    unsafe {ALLOCATOR.inner.init(heap_start, HEAP_SIZE)};
+    Ok(())
+}
+
+// start of the heap region, panics before `init_heap`
+pub fn heap_start() -> usize {
+    *HEAP_START.r#try().expect("the heap is not initialized")
+}
+
+// number of bytes currently mapped for the heap, 0 before `init_heap`
+pub fn heap_size() -> usize {
+    let heap_end = *HEAP_END.lock();
+    HEAP_START.r#try().map_or(0, |&heap_start| heap_end - heap_start)
 }
 
+// current usage of the global allocator
+pub fn stats() -> HeapStats {
+    ALLOCATOR.stats()
+}
 
+// the heap never grows beyond `max_size` bytes, already mapped memory is kept
+pub fn set_heap_limit(max_size: usize) {
+    assert!(max_size <= HEAP_RESERVED_SIZE, "heap limit is larger than the reserved heap region");
+    HEAP_LIMIT.store(max_size, Ordering::Relaxed);
+}
+
+pub fn heap_limit() -> usize {
+    HEAP_LIMIT.load(Ordering::Relaxed)
+}
 
+// call sites of the live allocations, recorded by `GrowableHeap`
+#[cfg(feature = "alloc-leak-tracker")]
+static LEAKS: leak::LeakTracker = leak::LeakTracker::new();
+
+// allocations made after the returned checkpoint can be checked with `leaks_since`
+#[cfg(feature = "alloc-leak-tracker")]
+pub fn leak_checkpoint() -> leak::LeakCheckpoint {
+    LEAKS.checkpoint()
+}
+
+// allocations made after the checkpoint that were not freed yet
+#[cfg(feature = "alloc-leak-tracker")]
+pub fn leaks_since(checkpoint: leak::LeakCheckpoint) -> leak::Leaks {
+    LEAKS.leaks_since(checkpoint)
+}
+
+// prints every live allocation grouped by call site over serial
+#[cfg(feature = "alloc-leak-tracker")]
+pub fn dump_leaks() {
+    LEAKS.dump_since(leak::LeakCheckpoint::BOOT);
+}
+
+#[cfg(feature = "alloc-leak-tracker")]
+pub fn dump_leaks_since(checkpoint: leak::LeakCheckpoint) {
+    LEAKS.dump_since(checkpoint);
+}
+
+// checks the guard bytes of every live allocation, panics if one of them was overwritten
+#[cfg(feature = "alloc-debug")]
+pub fn check_heap() {
+    ALLOCATOR.inner.check_all();
+}
+
+// number of allocations of the global allocator that were not freed yet
+#[cfg(feature = "alloc-debug")]
+pub fn live_allocations() -> usize {
+    ALLOCATOR.inner.live_allocations()
+}
+
+// 2 MiB aligned parts of the range are mapped with huge pages while large frames are free
+fn map_heap_pages(
+    start: usize,
+    size: usize,
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BitmapFrameAllocator,
+    ) -> Result<(), VmmError> {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        vmm::map_fresh(VirtAddr::new(start as u64), size as u64, flags, mapper, frame_allocator)
+}
+
+// wrapper that maps more memory after the end of the heap whenever the inner allocator runs out
+pub struct GrowableHeap<A> {
+    inner: A,
+    counters: AllocationCounters,
+}
+
+impl<A: HeapAllocator> GrowableHeap<A> {
+    pub const fn new(inner: A) -> Self {
+        GrowableHeap {
+            inner,
+            counters: AllocationCounters::new(),
+        }
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        // read before the allocator is locked, a slab cache keeps its lock while it takes a page from the heap
+        let slab_caches = slab::reported_stats();
+        self.counters.stats(heap_size(), self.inner.free_stats(), slab_caches)
+    }
+
+    // maps enough pages for an allocation with the given layout and adds them to the heap
+    fn grow(&self, layout: Layout) -> bool {
+        let mut heap_end = HEAP_END.lock();
+        let Some(&heap_start) = HEAP_START.r#try() else {
+            return false;
+        };
+
+        // the alignment might waste up to `align` bytes at the start of the new memory,
+        // the extra page leaves room for allocator overhead like the guards of the debug allocator
+        let required = layout.size().saturating_add(layout.align()).saturating_add(PAGE_SIZE);
+        let by = _align_up(required.max(HEAP_GROWTH_STEP), PAGE_SIZE);
+        if *heap_end + by - heap_start > heap_limit() {
+            return false;
+        }
+
+        // the kernel memory lock is taken with `try`, an allocation while it is held must
+        // fail instead of deadlocking
+        let mapped = memory::try_with_kernel_memory(|mapper, frame_allocator| {
+            map_heap_pages(*heap_end, by, mapper, frame_allocator)
+        });
+        match mapped {
+            Some(Ok(())) => {
+                unsafe {self.inner.extend(by)};
+                *heap_end += by;
+                true
+            }
+            // pages mapped before a failure are unmapped again
+            _ => false,
+        }
+    }
+}
+
+unsafe impl<A: GlobalAlloc + HeapAllocator> GlobalAlloc for GrowableHeap<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut ptr = self.inner.alloc(layout);
+        if ptr.is_null() && self.grow(layout) {
+            ptr = self.inner.alloc(layout);
+        }
+        if !ptr.is_null() {
+            self.counters.record_alloc(layout.size());
+            #[cfg(feature = "alloc-leak-tracker")]
+            LEAKS.record_alloc(ptr, layout.size(), leak::call_site());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "alloc-leak-tracker")]
+        LEAKS.record_dealloc(ptr);
+        self.inner.dealloc(ptr, layout);
+        self.counters.record_dealloc(layout.size());
+    }
+
+    // forwarded so allocators can resize in place
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let mut new_ptr = self.inner.realloc(ptr, layout, new_size);
+        if new_ptr.is_null() {
+            let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+            if self.grow(new_layout) {
+                new_ptr = self.inner.realloc(ptr, layout, new_size);
+            }
+        }
+        if !new_ptr.is_null() {
+            self.counters.record_realloc(layout.size(), new_size);
+            #[cfg(feature = "alloc-leak-tracker")]
+            LEAKS.record_realloc(ptr, new_ptr, new_size, leak::call_site());
+        }
+        new_ptr
+    }
+}
 
 // wrapper around spin::Mutex to permit trait implementations
 pub struct Locked<A> {
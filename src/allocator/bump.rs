@@ -1,4 +1,5 @@
-use super::{_align_up, Locked};
+use super::{_align_up, HeapAllocator, Locked};
+use super::stats::FreeStats;
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr;
 
@@ -19,6 +20,9 @@ impl BumpAllocator {
             allocations: 0,
         }
     }
+    /// # Safety
+    /// The caller must guarantee that the given heap range is mapped and unused.
+    /// Must be called only once.
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize){
         self.heap_start = heap_start;
         self.heap_end = heap_start + heap_size;
@@ -26,6 +30,33 @@ impl BumpAllocator {
     }
 }
 
+impl Default for BumpAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HeapAllocator for Locked<BumpAllocator> {
+    unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+        self.lock().init(heap_start, heap_size);
+    }
+
+    unsafe fn extend(&self, by: usize) {
+        self.lock().heap_end += by;
+    }
+
+    fn free_stats(&self) -> FreeStats {
+        let bump = self.lock();
+        // memory below `next` is only reused once every allocation is freed
+        let remaining = bump.heap_end - bump.next;
+        FreeStats {
+            bytes_free: remaining,
+            largest_free_block: Some(remaining),
+            size_classes: None,
+        }
+    }
+}
+
 unsafe impl GlobalAlloc for Locked<BumpAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         // get a mutable reference to a mutex guard for bump
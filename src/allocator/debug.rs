@@ -0,0 +1,212 @@
+use core::ptr;
+use alloc::alloc::{GlobalAlloc, Layout};
+use spin::Mutex;
+
+use super::{_align_up, HeapAllocator};
+use super::stats::FreeStats;
+use super::table::AddressTable;
+
+// guard bytes in front of and behind every allocation
+const GUARD_SIZE: usize = 16;
+const GUARD_BYTE: u8 = 0xFD;
+// fresh allocations are filled so reads of uninitialized memory stand out
+const ALLOC_BYTE: u8 = 0xCD;
+// freed memory is filled so use after free stands out
+const POISON_BYTE: u8 = 0xDD;
+
+// default number of live allocations that can be tracked
+pub const MAX_ALLOCATIONS: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Corruption {
+    // the guard in front of the allocation was overwritten
+    Underflow,
+    // the guard behind the allocation was overwritten
+    Overflow,
+}
+
+// opt-in wrapper (`alloc-debug` feature) that checks every allocation for heap corruption
+// each allocation gets guard bytes in front and behind it, which are checked when it is freed
+// freed memory is poisoned and live allocations are tracked to catch double and mismatched frees
+pub struct DebugAllocator<A, const N: usize = MAX_ALLOCATIONS> {
+    inner: A,
+    // layout of every live allocation
+    live: Mutex<AddressTable<Layout, N>>,
+}
+
+// the front guard is padded to the alignment, so the returned pointer stays aligned
+fn front_size(layout: Layout) -> usize {
+    _align_up(GUARD_SIZE, layout.align())
+}
+
+fn guarded_layout(layout: Layout) -> Layout {
+    let size = front_size(layout) + layout.size() + GUARD_SIZE;
+    Layout::from_size_align(size, layout.align()).expect("debug allocator: layout overflow")
+}
+
+/// Checks the guard bytes around an allocation of a `DebugAllocator`.
+///
+/// # Safety
+/// `ptr` must be a live allocation of a `DebugAllocator` with the given layout.
+pub unsafe fn check_guards(ptr: *mut u8, layout: Layout) -> Result<(), Corruption> {
+    let front_size = front_size(layout);
+    let front = core::slice::from_raw_parts(ptr.sub(front_size), front_size);
+    let back = core::slice::from_raw_parts(ptr.add(layout.size()), GUARD_SIZE);
+    if front.iter().any(|&b| b != GUARD_BYTE) {
+        return Err(Corruption::Underflow);
+    }
+    if back.iter().any(|&b| b != GUARD_BYTE) {
+        return Err(Corruption::Overflow);
+    }
+    Ok(())
+}
+
+impl<A, const N: usize> DebugAllocator<A, N> {
+    pub const fn new(inner: A) -> Self {
+        DebugAllocator {
+            inner,
+            live: Mutex::new(AddressTable::new(Layout::new::<u8>())),
+        }
+    }
+
+    // checks the guards of every live allocation and panics on the first corrupted one
+    pub fn check_all(&self) {
+        let live = self.live.lock();
+        for (ptr, layout) in live.iter() {
+            let ptr = ptr as *mut u8;
+            if let Err(corruption) = unsafe {check_guards(ptr, layout)} {
+                panic!("heap corruption ({corruption:?}) of allocation {ptr:p} with {layout:?}");
+            }
+        }
+    }
+
+    // number of allocations that were not freed yet
+    pub fn live_allocations(&self) -> usize {
+        self.live.lock().len()
+    }
+}
+
+impl<A: HeapAllocator, const N: usize> HeapAllocator for DebugAllocator<A, N> {
+    unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+        self.inner.init(heap_start, heap_size);
+    }
+
+    unsafe fn extend(&self, by: usize) {
+        self.inner.extend(by);
+    }
+
+    fn free_stats(&self) -> FreeStats {
+        self.inner.free_stats()
+    }
+}
+
+unsafe impl<A: GlobalAlloc, const N: usize> GlobalAlloc for DebugAllocator<A, N> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let guarded = guarded_layout(layout);
+        let block = self.inner.alloc(guarded);
+        if block.is_null() {
+            return block;
+        }
+
+        let front_size = front_size(layout);
+        let ptr = block.add(front_size);
+        ptr::write_bytes(block, GUARD_BYTE, front_size);
+        ptr::write_bytes(ptr, ALLOC_BYTE, layout.size());
+        ptr::write_bytes(ptr.add(layout.size()), GUARD_BYTE, GUARD_SIZE);
+
+        if !self.live.lock().insert(ptr as usize, layout) {
+            panic!("debug allocator: more than {} live allocations", N - 1);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        {
+            let mut live = self.live.lock();
+            let index = match live.find(ptr as usize) {
+                Some(index) => index,
+                None => panic!("double free or invalid free of {ptr:p} with {layout:?}"),
+            };
+            let allocated = live.remove(index);
+            if allocated != layout {
+                panic!("allocation {ptr:p} with {allocated:?} freed with {layout:?}");
+            }
+        }
+
+        if let Err(corruption) = check_guards(ptr, layout) {
+            panic!("heap corruption ({corruption:?}) of allocation {ptr:p} with {layout:?}");
+        }
+
+        let block = ptr.sub(front_size(layout));
+        let guarded = guarded_layout(layout);
+        ptr::write_bytes(block, POISON_BYTE, guarded.size());
+        self.inner.dealloc(block, guarded);
+    }
+}
+
+
+// the tests wrap their own allocator on a stack buffer, so they don't depend on the global allocator
+
+#[cfg(test)]
+use super::{Locked, linked_list::LinkedListAllocator};
+
+#[cfg(test)]
+#[repr(align(16))]
+struct Arena([u8; 1024]);
+
+// a small table, the default one is too large for the stack
+#[cfg(test)]
+fn test_allocator(arena: &mut Arena) -> DebugAllocator<Locked<LinkedListAllocator>, 64> {
+    let allocator = DebugAllocator::new(Locked::new(LinkedListAllocator::new()));
+    unsafe {allocator.init(arena.0.as_mut_ptr() as usize, arena.0.len())};
+    allocator
+}
+
+#[test_case]
+fn test_guards_are_intact() {
+    let mut arena = Arena([0; 1024]);
+    let allocator = test_allocator(&mut arena);
+    let layout = Layout::from_size_align(40, 8).unwrap();
+    unsafe {
+        let ptr = allocator.alloc(layout);
+        ptr.write_bytes(0x42, 40);
+        assert_eq!(check_guards(ptr, layout), Ok(()));
+        assert_eq!(allocator.live_allocations(), 1);
+        allocator.check_all();
+        allocator.dealloc(ptr, layout);
+        assert_eq!(allocator.live_allocations(), 0);
+        // freed memory is poisoned
+        assert_eq!(*ptr.add(20), POISON_BYTE);
+    }
+}
+
+#[test_case]
+fn test_overflow_and_underflow_are_detected() {
+    let mut arena = Arena([0; 1024]);
+    let allocator = test_allocator(&mut arena);
+    let layout = Layout::from_size_align(40, 8).unwrap();
+    unsafe {
+        let ptr = allocator.alloc(layout);
+        ptr.add(40).write(0);
+        assert_eq!(check_guards(ptr, layout), Err(Corruption::Overflow));
+        ptr.add(40).write(GUARD_BYTE);
+
+        ptr.sub(1).write(0);
+        assert_eq!(check_guards(ptr, layout), Err(Corruption::Underflow));
+        ptr.sub(1).write(GUARD_BYTE);
+
+        allocator.dealloc(ptr, layout);
+    }
+}
+
+#[test_case]
+fn test_aligned_allocations_stay_aligned() {
+    let mut arena = Arena([0; 1024]);
+    let allocator = test_allocator(&mut arena);
+    let layout = Layout::from_size_align(32, 64).unwrap();
+    unsafe {
+        let ptr = allocator.alloc(layout);
+        assert_eq!(ptr as usize % 64, 0);
+        allocator.dealloc(ptr, layout);
+    }
+}
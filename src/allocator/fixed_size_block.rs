@@ -1,4 +1,5 @@
-use super::Locked;
+use super::{HeapAllocator, Locked};
+use super::stats::{FreeStats, SizeClassStats};
 use core::{mem, ptr};
 use alloc::alloc::{GlobalAlloc, Layout};
 
@@ -8,11 +9,14 @@ struct ListNode {
 
 // block sizes start from 8 bytes, since each block would need to store a 64-bit pointer to a ListNode
 // for allocations greater than 2048 bytes we will fall back to the linked list allocator
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 
 pub struct FixedSizeBlockAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
+    // number of handed out and of free blocks for each block size
+    allocated_blocks: [usize; BLOCK_SIZES.len()],
+    free_blocks: [usize; BLOCK_SIZES.len()],
 }
 
 impl FixedSizeBlockAllocator {
@@ -20,9 +24,14 @@ impl FixedSizeBlockAllocator {
         const EMPTY: Option<&'static mut ListNode> = None;
         FixedSizeBlockAllocator { 
             list_heads: [EMPTY; BLOCK_SIZES.len()], 
-            fallback_allocator: linked_list_allocator::Heap::empty() 
+            fallback_allocator: linked_list_allocator::Heap::empty(),
+            allocated_blocks: [0; BLOCK_SIZES.len()],
+            free_blocks: [0; BLOCK_SIZES.len()],
         }
     }
+    /// # Safety
+    /// The caller must guarantee that the given heap range is mapped and unused.
+    /// Must be called only once.
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize){
         self.fallback_allocator.init(heap_start, heap_size);
     }
@@ -34,12 +43,49 @@ impl FixedSizeBlockAllocator {
         }
     }
 
+    // the largest free block is not reported, the holes of the fallback allocator are private to its crate
+    pub fn free_stats(&self) -> FreeStats {
+        let mut size_classes = [SizeClassStats::default(); BLOCK_SIZES.len()];
+        let mut bytes_free = self.fallback_allocator.free();
+        for (index, class) in size_classes.iter_mut().enumerate() {
+            class.block_size = BLOCK_SIZES[index];
+            class.allocated = self.allocated_blocks[index];
+            class.free = self.free_blocks[index];
+            bytes_free += class.free * class.block_size;
+        }
+        FreeStats {
+            bytes_free,
+            largest_free_block: None,
+            size_classes: Some(size_classes),
+        }
+    }
+
     fn list_index(layout: &Layout) -> Option<usize> {
         let require_block_size = layout.size().max(layout.align());
         BLOCK_SIZES.iter().position(|&s| s>= require_block_size)
     }
 }
 
+impl Default for FixedSizeBlockAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HeapAllocator for Locked<FixedSizeBlockAllocator> {
+    unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+        self.lock().init(heap_start, heap_size);
+    }
+
+    unsafe fn extend(&self, by: usize) {
+        self.lock().fallback_allocator.extend(by);
+    }
+
+    fn free_stats(&self) -> FreeStats {
+        self.lock().free_stats()
+    }
+}
+
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
 
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
@@ -54,6 +100,8 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
                         // head of list exists, so we pop and return it 
                         // then make the next node the new head
                         allocator.list_heads[index] = node.next.take();
+                        allocator.free_blocks[index] -= 1;
+                        allocator.allocated_blocks[index] += 1;
                         node as *mut ListNode as *mut u8
                     }
                     None => {
@@ -67,7 +115,11 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
                         let block_size = BLOCK_SIZES[index];
                         let block_align = block_size;
                         let layout = Layout::from_size_align(block_size, block_align).unwrap();
-                        allocator.fallback_alloc(layout)
+                        let ptr = allocator.fallback_alloc(layout);
+                        if !ptr.is_null() {
+                            allocator.allocated_blocks[index] += 1;
+                        }
+                        ptr
                     }
                 }
             }
@@ -101,6 +153,8 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
 
                 // head of the list is now None so we set the new node as the new head of the list 
                 allocator.list_heads[index] = Some(&mut *new_node_ptr);
+                allocator.allocated_blocks[index] -= 1;
+                allocator.free_blocks[index] += 1;
             }
             None => {
                 // deallocated memory is larger than the largest fixed size block
@@ -114,4 +168,31 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
         }
 
     }
-}
\ No newline at end of file
+}
+
+// the test uses its own allocator on a stack buffer, so it doesn't depend on the global allocator
+#[cfg(test)]
+#[repr(align(64))]
+struct Arena([u8; 4096]);
+
+#[test_case]
+fn test_free_stats_count_size_classes_and_fallback() {
+    let mut arena = Arena([0; 4096]);
+    let allocator = Locked::new(FixedSizeBlockAllocator::new());
+    unsafe {allocator.lock().init(arena.0.as_mut_ptr() as usize, arena.0.len())};
+    let stats = allocator.lock().free_stats();
+    assert_eq!(stats.bytes_free, 4096);
+    assert_eq!(stats.largest_free_block, None);
+
+    let block = Layout::from_size_align(64, 8).unwrap();
+    unsafe {
+        let small = allocator.alloc(block);
+        assert_eq!(allocator.lock().free_stats().bytes_free, 4096 - 64);
+        // the freed block goes to its size class and still counts as free
+        allocator.dealloc(small, block);
+    }
+    let stats = allocator.lock().free_stats();
+    assert_eq!(stats.bytes_free, 4096);
+    let class = stats.size_classes.unwrap()[3];
+    assert_eq!((class.block_size, class.allocated, class.free), (64, 0, 1));
+}
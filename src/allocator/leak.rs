@@ -0,0 +1,193 @@
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+
+use super::table::AddressTable;
+use crate::serial_println;
+
+// number of return addresses recorded for every allocation
+pub const BACKTRACE_DEPTH: usize = 8;
+// live allocations beyond this are counted but not tracked
+const MAX_TRACKED: usize = 4096;
+// a saved frame pointer further away than this is treated as the end of the stack
+const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+// return addresses of the frames that led to an allocation, innermost first
+// the first frames are the allocator and `alloc` internals, resolve the addresses with
+// `addr2line -e target/x86_64-rust-kernel/debug/rust-kernel <address>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallSite([usize; BACKTRACE_DEPTH]);
+
+impl CallSite {
+    pub fn return_addresses(&self) -> impl Iterator<Item = usize> + '_ {
+        self.0.iter().copied().take_while(|&address| address != 0)
+    }
+}
+
+// walks the frame pointer chain of the current stack, the kernel is built with frame pointers
+#[inline(always)]
+pub fn call_site() -> CallSite {
+    let mut addresses = [0; BACKTRACE_DEPTH];
+    let mut frame: usize;
+    unsafe {asm!("mov {}, rbp", out(reg) frame, options(nomem, nostack, preserves_flags))};
+
+    for address in addresses.iter_mut() {
+        if frame == 0 || !frame.is_multiple_of(8) {
+            break;
+        }
+        // a frame holds the frame pointer of the caller followed by the return address
+        let (caller_frame, return_address) = unsafe {
+            (*(frame as *const usize), *((frame + 8) as *const usize))
+        };
+        if return_address == 0 {
+            break;
+        }
+        *address = return_address;
+        // the stack grows down, so the frame of the caller is always above the current one
+        if caller_frame <= frame || caller_frame - frame > MAX_FRAME_SIZE {
+            break;
+        }
+        frame = caller_frame;
+    }
+    CallSite(addresses)
+}
+
+#[derive(Clone, Copy)]
+struct Record {
+    size: usize,
+    // allocations are numbered, so a checkpoint can tell old allocations from new ones
+    sequence: u64,
+    call_site: CallSite,
+}
+
+// returned by `allocator::leak_checkpoint()`, only allocations made after it count as leaks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LeakCheckpoint(u64);
+
+impl LeakCheckpoint {
+    // every allocation since boot
+    pub const BOOT: LeakCheckpoint = LeakCheckpoint(0);
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Leaks {
+    pub allocations: usize,
+    pub bytes: usize,
+}
+
+// records the call site of every live allocation of the global allocator (`alloc-leak-tracker` feature)
+pub struct LeakTracker {
+    live: Mutex<AddressTable<Record, MAX_TRACKED>>,
+    next_sequence: AtomicU64,
+    // allocations that didn't fit into the table
+    untracked: AtomicUsize,
+}
+
+impl LeakTracker {
+    pub const fn new() -> Self {
+        LeakTracker {
+            live: Mutex::new(AddressTable::new(Record {
+                size: 0,
+                sequence: 0,
+                call_site: CallSite([0; BACKTRACE_DEPTH]),
+            })),
+            next_sequence: AtomicU64::new(0),
+            untracked: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn record_alloc(&self, ptr: *mut u8, size: usize, call_site: CallSite) {
+        let sequence = self.next_sequence.fetch_add(1, Ordering::Relaxed);
+        if !self.live.lock().insert(ptr as usize, Record { size, sequence, call_site }) {
+            self.untracked.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_dealloc(&self, ptr: *mut u8) {
+        let mut live = self.live.lock();
+        // untracked allocations are not in the table
+        if let Some(index) = live.find(ptr as usize) {
+            live.remove(index);
+        }
+    }
+
+    // the allocation keeps its sequence number, growing an old allocation is no leak
+    pub fn record_realloc(&self, ptr: *mut u8, new_ptr: *mut u8, new_size: usize, call_site: CallSite) {
+        let mut live = self.live.lock();
+        let sequence = match live.find(ptr as usize) {
+            Some(index) => live.remove(index).sequence,
+            None => return,
+        };
+        if !live.insert(new_ptr as usize, Record { size: new_size, sequence, call_site }) {
+            self.untracked.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn checkpoint(&self) -> LeakCheckpoint {
+        LeakCheckpoint(self.next_sequence.load(Ordering::Relaxed))
+    }
+
+    // allocations made after the checkpoint that are still live
+    pub fn leaks_since(&self, checkpoint: LeakCheckpoint) -> Leaks {
+        let live = self.live.lock();
+        live.iter()
+            .filter(|(_, record)| record.sequence >= checkpoint.0)
+            .fold(Leaks::default(), |leaks, (_, record)| Leaks {
+                allocations: leaks.allocations + 1,
+                bytes: leaks.bytes + record.size,
+            })
+    }
+
+    // prints the allocations made after the checkpoint that are still live, grouped by call site
+    pub fn dump_since(&self, checkpoint: LeakCheckpoint) {
+        let live = self.live.lock();
+        let leaked = |record: &Record| record.sequence >= checkpoint.0;
+
+        let mut total = Leaks::default();
+        let mut call_sites = 0;
+        // the table can't be sorted without allocating, so every call site is
+        // printed at its first allocation and the others are skipped
+        for (index, (ptr, record)) in live.iter().enumerate() {
+            if !leaked(&record) {
+                continue;
+            }
+            total.allocations += 1;
+            total.bytes += record.size;
+            let seen = live.iter().take(index)
+                .any(|(_, other)| leaked(&other) && other.call_site == record.call_site);
+            if seen {
+                continue;
+            }
+
+            let site = live.iter().skip(index)
+                .filter(|(_, other)| leaked(other) && other.call_site == record.call_site)
+                .fold(Leaks::default(), |leaks, (_, other)| Leaks {
+                    allocations: leaks.allocations + 1,
+                    bytes: leaks.bytes + other.size,
+                });
+            call_sites += 1;
+            serial_println!(
+                "leak: {} allocations, {} bytes (e.g. {:#x})",
+                site.allocations, site.bytes, ptr
+            );
+            for address in record.call_site.return_addresses() {
+                serial_println!("    at {:#x}", address);
+            }
+        }
+
+        serial_println!(
+            "leaks: {} allocations, {} bytes from {} call sites",
+            total.allocations, total.bytes, call_sites
+        );
+        let untracked = self.untracked.load(Ordering::Relaxed);
+        if untracked > 0 {
+            serial_println!("leaks: {} allocations were not tracked, the table was full", untracked);
+        }
+    }
+}
+
+impl Default for LeakTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,5 +1,6 @@
 use core::{mem, ptr};
-use super::{_align_up, Locked};
+use super::{_align_up, HeapAllocator, Locked};
+use super::stats::FreeStats;
 use alloc::alloc::{GlobalAlloc, Layout};
 
 
@@ -10,7 +11,7 @@ struct ListNode {
 
 impl ListNode {
     const fn new(size: usize) -> Self {
-        ListNode { size: size, next: None }
+        ListNode { size, next: None }
     }
 
     fn start_addr(&self) -> usize {
@@ -24,35 +25,147 @@ impl ListNode {
 
 pub struct LinkedListAllocator {
     head: ListNode,
+    heap_end: usize,
 }
 
 impl LinkedListAllocator {
     pub const fn new() -> Self {
         Self {
             head: ListNode::new(0),
+            heap_end: 0,
         }
     }
     
+    /// # Safety
+    /// The caller must guarantee that the given heap range is mapped and unused.
+    /// Must be called only once.
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize){
         self.add_free_region(heap_start, heap_size);
+        self.heap_end = heap_start + heap_size;
     }
 
+    /// # Safety
+    /// The caller must guarantee that the `by` bytes after the end of the heap are mapped and unused.
+    pub unsafe fn extend(&mut self, by: usize){
+        self.add_free_region(self.heap_end, by);
+        self.heap_end += by;
+    }
+
+    // walks the free list, so this takes time linear in the number of free regions
+    pub fn free_stats(&self) -> FreeStats {
+        let mut bytes_free = 0;
+        let mut largest_free_block = 0;
+        let mut current = self.head.next.as_deref();
+        while let Some(region) = current {
+            bytes_free += region.size;
+            largest_free_block = largest_free_block.max(region.size);
+            current = region.next.as_deref();
+        }
+        FreeStats {
+            bytes_free,
+            largest_free_block: Some(largest_free_block),
+            size_classes: None,
+        }
+    }
+
+    // inserts the region into the free list, which is kept sorted by address,
+    // and merges it with the free regions directly before and after it
     unsafe fn add_free_region(&mut self, addr: usize, size: usize){
         // ensure that the freed region is large enough to hold a ListNode 
         assert_eq!(_align_up(addr, mem::align_of::<ListNode>()), addr);
         assert!(size >= mem::size_of::<ListNode>());
 
-        let mut node = ListNode::new(size);
-        node.next = self.head.next.take();
-        let node_ptr = addr as *mut ListNode;
-        node_ptr.write(node);
-        self.head.next = Some(&mut *node_ptr);
+        // find the last region that starts before the freed one
+        let head_ptr = &self.head as *const ListNode;
+        let mut previous = &mut self.head;
+        while previous.next.as_ref().is_some_and(|next| next.start_addr() < addr) {
+            previous = previous.next.as_mut().unwrap();
+        }
+        let is_head = ptr::eq(&*previous, head_ptr);
+
+        // a freed region can never overlap a free one, unless it is freed twice
+        assert!(is_head || previous.end_addr() <= addr, "freed region {addr:#x} overlaps a free region");
+        assert!(
+            previous.next.as_ref().is_none_or(|next| addr + size <= next.start_addr()),
+            "freed region {addr:#x} overlaps a free region"
+        );
+
+        let mut size = size;
+        let mut next = previous.next.take();
+
+        // merge with the following region
+        if next.as_ref().is_some_and(|following| following.start_addr() == addr + size) {
+            let following = next.take().unwrap();
+            size += following.size;
+            next = following.next.take();
+        }
+
+        // merge with the previous region, the head is only a dummy node and never merged
+        if !is_head && previous.end_addr() == addr {
+            previous.size += size;
+            previous.next = next;
+        }
+        else {
+            let node_ptr = addr as *mut ListNode;
+            node_ptr.write(ListNode { size, next });
+            previous.next = Some(&mut *node_ptr);
+        }
+    }
+
+    // extends the allocation `addr..addr + size` to `new_size` bytes
+    // by taking memory from the free region directly after it
+    unsafe fn grow_in_place(&mut self, addr: usize, size: usize, new_size: usize) -> bool {
+        let end = addr + size;
+        let required = new_size - size;
+
+        let mut previous = &mut self.head;
+        while previous.next.as_ref().is_some_and(|next| next.start_addr() < end) {
+            previous = previous.next.as_mut().unwrap();
+        }
+
+        let excess_size = match previous.next.as_ref() {
+            Some(following) if following.start_addr() == end && following.size >= required => {
+                following.size - required
+            }
+            // the memory after the allocation is used or too small
+            _ => return false,
+        };
+        if excess_size > 0 && excess_size < mem::size_of::<ListNode>() {
+            // rest of the region is too small to hold a ListNode
+            return false;
+        }
+
+        let following = previous.next.take().unwrap();
+        let next = following.next.take();
+        if excess_size > 0 {
+            let node_ptr = (end + required) as *mut ListNode;
+            node_ptr.write(ListNode { size: excess_size, next });
+            previous.next = Some(&mut *node_ptr);
+        }
+        else {
+            previous.next = next;
+        }
+        true
+    }
+
+    // gives the end of the allocation `addr..addr + size` back, so it ends at `addr + new_size`
+    unsafe fn shrink_in_place(&mut self, addr: usize, size: usize, new_size: usize) -> bool {
+        let excess_size = size - new_size;
+        if excess_size == 0 {
+            return true;
+        }
+        if excess_size < mem::size_of::<ListNode>() {
+            // the freed end can't hold a ListNode
+            return false;
+        }
+        self.add_free_region(addr + new_size, excess_size);
+        true
     }
 
     fn find_region(&mut self, size: usize, align: usize) -> Option<(&'static mut ListNode, usize)> {
         let mut current = &mut self.head;
         while let Some(ref mut region) = current.next {
-            if let Ok(alloc_start) = Self::alloc_from_region(&region, size,align) {
+            if let Ok(alloc_start) = Self::alloc_from_region(region, size,align) {
                 let next = region.next.take();
                 let ret = Some((current.next.take().unwrap(), alloc_start));
                 current.next = next;
@@ -68,7 +181,12 @@ impl LinkedListAllocator {
 
     fn alloc_from_region(region: &ListNode, size: usize, align: usize)
         -> Result<usize, ()> {
-            let alloc_start = _align_up(region.start_addr(), align);
+            let mut alloc_start = _align_up(region.start_addr(), align);
+            if alloc_start != region.start_addr() && alloc_start - region.start_addr() < mem::size_of::<ListNode>() {
+                // the gap in front of the allocation is too small to hold a ListNode
+                // move to the next aligned address, so the gap can be given back
+                alloc_start = _align_up(region.start_addr() + mem::size_of::<ListNode>(), align);
+            }
             let alloc_end = alloc_start.checked_add(size).ok_or(())?;
 
             if alloc_end > region.end_addr(){
@@ -96,6 +214,26 @@ impl LinkedListAllocator {
 
 }
 
+impl Default for LinkedListAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HeapAllocator for Locked<LinkedListAllocator> {
+    unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+        self.lock().init(heap_start, heap_size);
+    }
+
+    unsafe fn extend(&self, by: usize) {
+        self.lock().extend(by);
+    }
+
+    fn free_stats(&self) -> FreeStats {
+        self.lock().free_stats()
+    }
+}
+
 unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let (size, align) = LinkedListAllocator::size_align(layout);
@@ -103,7 +241,15 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
         
         if let Some((region, alloc_start)) = allocator.find_region(size, align){
             let alloc_end = alloc_start.checked_add(size).expect("overflow");
-            let excess_size = region.end_addr() - alloc_end;
+            let (region_start, region_end) = (region.start_addr(), region.end_addr());
+            let front_size = alloc_start - region_start;
+            let excess_size = region_end - alloc_end;
+            if front_size > 0 {
+                // padding in front of the allocation, caused by its alignment
+                unsafe {
+                    allocator.add_free_region(region_start, front_size);
+                }
+            }
             if excess_size > 0 {
                 unsafe {
                     allocator.add_free_region(alloc_end, excess_size);
@@ -122,4 +268,159 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
             self.lock().add_free_region(ptr as usize, size)
         }
     }
-}
\ No newline at end of file
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let (size, _) = LinkedListAllocator::size_align(layout);
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let (adjusted_new_size, _) = LinkedListAllocator::size_align(new_layout);
+        // both sizes round to the same block, nothing to do
+        if adjusted_new_size == size {
+            return ptr;
+        }
+
+        let resized = {
+            let mut allocator = self.lock();
+            if adjusted_new_size > size {
+                allocator.grow_in_place(ptr as usize, size, adjusted_new_size)
+            }
+            else {
+                allocator.shrink_in_place(ptr as usize, size, adjusted_new_size)
+            }
+        };
+        if resized {
+            return ptr;
+        }
+
+        // the neighbouring memory is used, move the allocation like the default implementation
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
+
+// the tests use their own allocator on a stack buffer, so they don't depend on the global allocator
+
+#[cfg(test)]
+#[repr(align(16))]
+struct Arena([u8; 1024]);
+
+#[cfg(test)]
+fn test_allocator(arena: &mut Arena) -> Locked<LinkedListAllocator> {
+    let allocator = Locked::new(LinkedListAllocator::new());
+    unsafe {allocator.lock().init(arena.0.as_mut_ptr() as usize, arena.0.len())};
+    allocator
+}
+
+// number of free regions, asserting that they are sorted and not adjacent
+#[cfg(test)]
+fn count_free_regions(allocator: &LinkedListAllocator) -> usize {
+    let mut count = 0;
+    let mut current = allocator.head.next.as_deref();
+    while let Some(region) = current {
+        if let Some(next) = region.next.as_deref() {
+            assert!(region.end_addr() < next.start_addr());
+        }
+        count += 1;
+        current = region.next.as_deref();
+    }
+    count
+}
+
+#[test_case]
+fn test_free_list_is_sorted_and_coalesced() {
+    let mut arena = Arena([0; 1024]);
+    let allocator = test_allocator(&mut arena);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let blocks = [(); 4].map(|_| unsafe {allocator.alloc(layout)});
+
+    // free out of order, every freed block ends up at its address order
+    unsafe {
+        allocator.dealloc(blocks[2], layout);
+        allocator.dealloc(blocks[0], layout);
+    }
+    assert_eq!(count_free_regions(&allocator.lock()), 3);
+
+    // freeing the rest merges everything back into a single region
+    unsafe {
+        allocator.dealloc(blocks[3], layout);
+        allocator.dealloc(blocks[1], layout);
+    }
+    let stats = allocator.lock().free_stats();
+    assert_eq!(count_free_regions(&allocator.lock()), 1);
+    assert_eq!(stats.bytes_free, 1024);
+    assert_eq!(stats.largest_free_block, Some(1024));
+}
+
+#[test_case]
+fn test_realloc_grows_in_place() {
+    let mut arena = Arena([0; 1024]);
+    let allocator = test_allocator(&mut arena);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    unsafe {
+        let ptr = allocator.alloc(layout);
+        ptr.write_bytes(0x42, 64);
+        let grown = allocator.realloc(ptr, layout, 256);
+        assert_eq!(grown, ptr);
+        assert_eq!(*grown.add(63), 0x42);
+
+        let layout = Layout::from_size_align(256, 8).unwrap();
+        let shrunk = allocator.realloc(grown, layout, 128);
+        assert_eq!(shrunk, ptr);
+        allocator.dealloc(shrunk, Layout::from_size_align(128, 8).unwrap());
+    }
+    assert_eq!(allocator.lock().free_stats().largest_free_block, Some(1024));
+}
+
+#[test_case]
+fn test_realloc_moves_when_blocked() {
+    let mut arena = Arena([0; 1024]);
+    let allocator = test_allocator(&mut arena);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    unsafe {
+        let ptr = allocator.alloc(layout);
+        let blocker = allocator.alloc(layout);
+        ptr.write_bytes(0x42, 64);
+        let moved = allocator.realloc(ptr, layout, 256);
+        assert_ne!(moved, ptr);
+        assert_eq!(*moved.add(63), 0x42);
+
+        allocator.dealloc(moved, Layout::from_size_align(256, 8).unwrap());
+        allocator.dealloc(blocker, layout);
+    }
+    assert_eq!(allocator.lock().free_stats().largest_free_block, Some(1024));
+}
+
+#[test_case]
+fn test_alignment_padding_is_freed() {
+    let mut arena = Arena([0; 1024]);
+    let allocator = test_allocator(&mut arena);
+    let small = Layout::from_size_align(16, 8).unwrap();
+    let aligned = Layout::from_size_align(64, 256).unwrap();
+    unsafe {
+        let first = allocator.alloc(small);
+        let second = allocator.alloc(aligned);
+        assert_eq!(second as usize % 256, 0);
+        allocator.dealloc(first, small);
+        allocator.dealloc(second, aligned);
+    }
+    assert_eq!(allocator.lock().free_stats().largest_free_block, Some(1024));
+}
+
+#[test_case]
+fn test_realloc_to_the_same_block_size() {
+    let mut arena = Arena([0; 1024]);
+    let allocator = test_allocator(&mut arena);
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    unsafe {
+        let ptr = allocator.alloc(layout);
+        let free = allocator.lock().free_stats().bytes_free;
+        // 60 bytes round up to the same 64 byte block
+        assert_eq!(allocator.realloc(ptr, layout, 60), ptr);
+        assert_eq!(allocator.lock().free_stats().bytes_free, free);
+        allocator.dealloc(ptr, Layout::from_size_align(60, 8).unwrap());
+    }
+    assert_eq!(allocator.lock().free_stats().largest_free_block, Some(1024));
+}
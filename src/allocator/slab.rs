@@ -0,0 +1,331 @@
+use core::{fmt, mem, ptr};
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, Ordering};
+use alloc::alloc::{alloc, dealloc, Layout};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::{_align_up, PAGE_SIZE};
+use super::stats::MAX_REPORTED_CACHES;
+
+// every slab is a single page taken from the heap
+// the header sits at the start of the page, followed by the objects
+// so the slab of an object is found by rounding its address down to the page
+struct SlabHeader {
+    // neighbours in the list of slabs that have free objects
+    next: *mut SlabHeader,
+    prev: *mut SlabHeader,
+    free: *mut FreeObject,
+    in_use: usize,
+}
+
+// free objects hold the link to the next free object of their slab
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+struct SlabLists {
+    // slabs with at least one free object, full slabs are only reachable through their objects
+    partial: *mut SlabHeader,
+    slabs: usize,
+    objects_in_use: usize,
+}
+
+// the raw pointers are only accessed while the cache lock is held
+unsafe impl Send for SlabLists {}
+
+// cache of equally sized objects, the untyped part of `ObjectCache`
+pub struct SlabCache {
+    name: &'static str,
+    object_size: usize,
+    object_align: usize,
+    lists: Mutex<SlabLists>,
+    registered: AtomicBool,
+}
+
+// caches that created at least one slab, so their stats can be listed
+static CACHES: Mutex<Vec<&'static SlabCache>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy)]
+pub struct SlabStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    pub slabs: usize,
+    pub objects_in_use: usize,
+    pub objects_free: usize,
+}
+
+impl SlabCache {
+    // the objects have to fit in a page next to the header, caches declared as statics fail to build otherwise
+    pub const fn new(name: &'static str, object_size: usize, object_align: usize) -> Self {
+        // a free object has to hold a `FreeObject`
+        let object_align = if object_align > mem::align_of::<FreeObject>() {
+            object_align
+        } else {
+            mem::align_of::<FreeObject>()
+        };
+        let object_size = if object_size > mem::size_of::<FreeObject>() {
+            object_size
+        } else {
+            mem::size_of::<FreeObject>()
+        };
+        let object_size = (object_size + object_align - 1) & !(object_align - 1);
+        let first_object_offset = (mem::size_of::<SlabHeader>() + object_align - 1) & !(object_align - 1);
+        assert!(first_object_offset + object_size <= PAGE_SIZE, "objects of the cache don't fit in a slab");
+        SlabCache {
+            name,
+            object_size,
+            object_align,
+            lists: Mutex::new(SlabLists {
+                partial: ptr::null_mut(),
+                slabs: 0,
+                objects_in_use: 0,
+            }),
+            registered: AtomicBool::new(false),
+        }
+    }
+
+    fn first_object_offset(&self) -> usize {
+        _align_up(mem::size_of::<SlabHeader>(), self.object_align)
+    }
+
+    pub fn objects_per_slab(&self) -> usize {
+        (PAGE_SIZE - self.first_object_offset()) / self.object_size
+    }
+
+    // returns uninitialized memory for a single object, `None` if the heap is exhausted
+    pub fn alloc(&'static self) -> Option<NonNull<u8>> {
+        if self.objects_per_slab() == 0 {
+            return None;
+        }
+
+        let object = {
+            let mut lists = self.lists.lock();
+            if lists.partial.is_null() {
+                let slab = self.new_slab()?;
+                unsafe {Self::push_partial(&mut lists, slab)};
+                lists.slabs += 1;
+            }
+
+            unsafe {
+                let slab = &mut *lists.partial;
+                let object = slab.free;
+                slab.free = (*object).next;
+                slab.in_use += 1;
+                if slab.free.is_null() {
+                    // the slab is full now
+                    Self::remove_partial(&mut lists, slab);
+                }
+                lists.objects_in_use += 1;
+                object as *mut u8
+            }
+        };
+
+        // registering allocates, so it happens outside of the cache lock
+        if !self.registered.swap(true, Ordering::Relaxed) {
+            CACHES.lock().push(self);
+        }
+        NonNull::new(object)
+    }
+
+    /// Returns an object to its slab, the page of the slab goes back to the heap once it is empty.
+    ///
+    /// # Safety
+    /// The object must have been allocated from this cache and must not be used anymore.
+    pub unsafe fn free(&self, object: NonNull<u8>) {
+        let object = object.as_ptr() as *mut FreeObject;
+        let slab = &mut *((object as usize & !(PAGE_SIZE - 1)) as *mut SlabHeader);
+        let mut lists = self.lists.lock();
+
+        let mut free = slab.free;
+        while !free.is_null() {
+            assert!(free != object, "double free of object {object:p} in cache {}", self.name);
+            free = (*free).next;
+        }
+
+        let was_full = slab.free.is_null();
+        object.write(FreeObject { next: slab.free });
+        slab.free = object;
+        slab.in_use -= 1;
+        lists.objects_in_use -= 1;
+
+        if slab.in_use == 0 {
+            if !was_full {
+                Self::remove_partial(&mut lists, slab);
+            }
+            lists.slabs -= 1;
+            dealloc(slab as *mut SlabHeader as *mut u8, Self::slab_layout());
+        }
+        else if was_full {
+            Self::push_partial(&mut lists, slab);
+        }
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        let lists = self.lists.lock();
+        let objects_per_slab = self.objects_per_slab();
+        SlabStats {
+            name: self.name,
+            object_size: self.object_size,
+            objects_per_slab,
+            slabs: lists.slabs,
+            objects_in_use: lists.objects_in_use,
+            objects_free: lists.slabs * objects_per_slab - lists.objects_in_use,
+        }
+    }
+
+    fn slab_layout() -> Layout {
+        Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap()
+    }
+
+    // takes a page from the heap and threads all of its objects onto the free list
+    fn new_slab(&self) -> Option<*mut SlabHeader> {
+        let page = unsafe {alloc(Self::slab_layout())};
+        if page.is_null() {
+            return None;
+        }
+
+        let first_object = page as usize + self.first_object_offset();
+        let mut free = ptr::null_mut();
+        for index in (0..self.objects_per_slab()).rev() {
+            let object = (first_object + index * self.object_size) as *mut FreeObject;
+            unsafe {object.write(FreeObject { next: free })};
+            free = object;
+        }
+
+        let slab = page as *mut SlabHeader;
+        unsafe {
+            slab.write(SlabHeader {
+                next: ptr::null_mut(),
+                prev: ptr::null_mut(),
+                free,
+                in_use: 0,
+            });
+        }
+        Some(slab)
+    }
+
+    unsafe fn push_partial(lists: &mut SlabLists, slab: *mut SlabHeader) {
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = lists.partial;
+        if !lists.partial.is_null() {
+            (*lists.partial).prev = slab;
+        }
+        lists.partial = slab;
+    }
+
+    unsafe fn remove_partial(lists: &mut SlabLists, slab: *mut SlabHeader) {
+        let (prev, next) = ((*slab).prev, (*slab).next);
+        if prev.is_null() {
+            lists.partial = next;
+        } else {
+            (*prev).next = next;
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+    }
+}
+
+// stats of every cache that has been used
+pub fn stats() -> Vec<SlabStats> {
+    let caches = CACHES.lock().clone();
+    caches.iter().map(|cache| cache.stats()).collect()
+}
+
+// stats of the first `MAX_REPORTED_CACHES` caches that have been used, without allocating
+// so they can be part of `allocator::stats()`
+pub(super) fn reported_stats() -> [Option<SlabStats>; MAX_REPORTED_CACHES] {
+    let mut reported = [None; MAX_REPORTED_CACHES];
+    let caches = CACHES.lock();
+    for (slot, cache) in reported.iter_mut().zip(caches.iter()) {
+        *slot = Some(cache.stats());
+    }
+    reported
+}
+
+impl fmt::Display for SlabStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "slab {}: {} objects of {} bytes in use, {} free in {} slabs",
+            self.name, self.objects_in_use, self.object_size, self.objects_free, self.slabs
+        )
+    }
+}
+
+// cache for objects of type `T`, every object is initialized with `constructor`
+// declared as a static, e.g.
+// static NODES: ObjectCache<Node> = ObjectCache::new("node", Node::default);
+pub struct ObjectCache<T> {
+    cache: SlabCache,
+    constructor: Option<fn() -> T>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> ObjectCache<T> {
+    pub const fn new(name: &'static str, constructor: fn() -> T) -> Self {
+        Self::with_constructor(name, Some(constructor))
+    }
+
+    // cache whose objects are only created with `alloc_with`
+    pub const fn without_constructor(name: &'static str) -> Self {
+        Self::with_constructor(name, None)
+    }
+
+    const fn with_constructor(name: &'static str, constructor: Option<fn() -> T>) -> Self {
+        ObjectCache {
+            cache: SlabCache::new(name, mem::size_of::<T>(), mem::align_of::<T>()),
+            constructor,
+            _marker: PhantomData,
+        }
+    }
+
+    // allocates and constructs an object, `None` if the heap is exhausted or the cache has no constructor
+    pub fn alloc(&'static self) -> Option<SlabBox<T>> {
+        let constructor = self.constructor?;
+        self.alloc_with(constructor())
+    }
+
+    // allocates an object and moves `value` into it, `None` if the heap is exhausted
+    pub fn alloc_with(&'static self, value: T) -> Option<SlabBox<T>> {
+        let object = self.cache.alloc()?.cast::<T>();
+        unsafe {object.as_ptr().write(value)};
+        Some(SlabBox { object, cache: self })
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        self.cache.stats()
+    }
+}
+
+// owned object from an `ObjectCache`, dropped and given back to the cache when it goes out of scope
+pub struct SlabBox<T: 'static> {
+    object: NonNull<T>,
+    cache: &'static ObjectCache<T>,
+}
+
+impl<T: 'static> Deref for SlabBox<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe {self.object.as_ref()}
+    }
+}
+
+impl<T: 'static> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe {self.object.as_mut()}
+    }
+}
+
+impl<T: 'static> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.object.as_ptr());
+            self.cache.cache.free(self.object.cast());
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::fixed_size_block::BLOCK_SIZES;
+use super::slab::SlabStats;
+
+// number of slab caches listed in `HeapStats`
+pub const MAX_REPORTED_CACHES: usize = 16;
+
+// snapshot of the heap usage, returned by `allocator::stats()`
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    // bytes currently mapped for the heap
+    pub heap_size: usize,
+    // bytes requested by live allocations
+    pub bytes_allocated: usize,
+    // bytes the allocator can still hand out, without growing the heap
+    pub bytes_free: usize,
+    // number of live allocations
+    pub allocations: usize,
+    // highest value `bytes_allocated` has reached
+    pub peak_allocated: usize,
+    // size of the largest free region, `None` if the allocator can't tell
+    pub largest_free_block: Option<usize>,
+    // per block size counts, only reported by the fixed size block allocator
+    pub size_classes: Option<[SizeClassStats; BLOCK_SIZES.len()]>,
+    // slab caches that have been used, their slabs are part of `bytes_allocated`
+    pub slab_caches: [Option<SlabStats>; MAX_REPORTED_CACHES],
+}
+
+impl HeapStats {
+    pub fn slab_caches(&self) -> impl Iterator<Item = &SlabStats> {
+        self.slab_caches.iter().flatten()
+    }
+}
+
+// free memory as seen by a single allocator, see `HeapAllocator::free_stats`
+#[derive(Debug, Clone, Copy)]
+pub struct FreeStats {
+    pub bytes_free: usize,
+    pub largest_free_block: Option<usize>,
+    pub size_classes: Option<[SizeClassStats; BLOCK_SIZES.len()]>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SizeClassStats {
+    pub block_size: usize,
+    // blocks of this size that are handed out
+    pub allocated: usize,
+    // blocks of this size waiting in the free list
+    pub free: usize,
+}
+
+// counts the allocations going through the global allocator
+// atomics so the counters can be read without taking the allocator lock
+pub struct AllocationCounters {
+    allocated: AtomicUsize,
+    allocations: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl AllocationCounters {
+    pub const fn new() -> Self {
+        AllocationCounters {
+            allocated: AtomicUsize::new(0),
+            allocations: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn record_alloc(&self, size: usize) {
+        let allocated = self.allocated.fetch_add(size, Ordering::Relaxed) + size;
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        self.peak.fetch_max(allocated, Ordering::Relaxed);
+    }
+
+    pub fn record_realloc(&self, old_size: usize, new_size: usize) {
+        let allocated = self.allocated.fetch_add(new_size, Ordering::Relaxed) + new_size;
+        self.allocated.fetch_sub(old_size, Ordering::Relaxed);
+        self.peak.fetch_max(allocated - old_size, Ordering::Relaxed);
+    }
+
+    pub fn record_dealloc(&self, size: usize) {
+        self.allocated.fetch_sub(size, Ordering::Relaxed);
+        self.allocations.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(
+        &self,
+        heap_size: usize,
+        free: FreeStats,
+        slab_caches: [Option<SlabStats>; MAX_REPORTED_CACHES],
+    ) -> HeapStats {
+        HeapStats {
+            heap_size,
+            bytes_allocated: self.allocated.load(Ordering::Relaxed),
+            bytes_free: free.bytes_free,
+            allocations: self.allocations.load(Ordering::Relaxed),
+            peak_allocated: self.peak.load(Ordering::Relaxed),
+            largest_free_block: free.largest_free_block,
+            size_classes: free.size_classes,
+            slab_caches,
+        }
+    }
+}
+
+impl Default for AllocationCounters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "heap: {} bytes in {} allocations (peak {}), {} of {} bytes free",
+            self.bytes_allocated, self.allocations, self.peak_allocated, self.bytes_free, self.heap_size
+        )?;
+        if let Some(largest) = self.largest_free_block {
+            write!(f, ", largest free block {largest}")?;
+        }
+        if let Some(size_classes) = self.size_classes {
+            for class in size_classes.iter() {
+                write!(f, "\n  {:>4} bytes: {} allocated, {} free", class.block_size, class.allocated, class.free)?;
+            }
+        }
+        for cache in self.slab_caches() {
+            write!(f, "\n  {cache}")?;
+        }
+        Ok(())
+    }
+}
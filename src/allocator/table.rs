@@ -0,0 +1,126 @@
+// open addressing hash table keyed by the address of an allocation
+// the allocator instrumentation can't use the heap it is tracking, so the capacity is fixed
+pub struct AddressTable<V, const N: usize> {
+    // 0 marks an empty slot
+    keys: [usize; N],
+    values: [V; N],
+    len: usize,
+}
+
+impl<V: Copy, const N: usize> AddressTable<V, N> {
+    // `empty` fills the unused slots
+    pub const fn new(empty: V) -> Self {
+        AddressTable {
+            keys: [0; N],
+            values: [empty; N],
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn home(key: usize) -> usize {
+        // fibonacci hashing, the low bits of heap pointers are mostly zero
+        (key.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 32) % N
+    }
+
+    // index of the slot holding `key`
+    pub fn find(&self, key: usize) -> Option<usize> {
+        let mut index = Self::home(key);
+        while self.keys[index] != 0 {
+            if self.keys[index] == key {
+                return Some(index);
+            }
+            index = (index + 1) % N;
+        }
+        None
+    }
+
+    pub fn get(&self, index: usize) -> V {
+        self.values[index]
+    }
+
+    // returns false if the table is full
+    pub fn insert(&mut self, key: usize, value: V) -> bool {
+        // one slot always stays empty, so lookups terminate
+        if self.len >= N - 1 {
+            return false;
+        }
+        let mut index = Self::home(key);
+        while self.keys[index] != 0 {
+            index = (index + 1) % N;
+        }
+        self.keys[index] = key;
+        self.values[index] = value;
+        self.len += 1;
+        true
+    }
+
+    pub fn remove(&mut self, mut index: usize) -> V {
+        let value = self.values[index];
+        self.keys[index] = 0;
+        self.len -= 1;
+
+        // move following entries back into the hole, so lookups never stop early
+        let mut next = (index + 1) % N;
+        while self.keys[next] != 0 {
+            let home = Self::home(self.keys[next]);
+            // the entry can move if its home is not between the hole and its current slot
+            let between = if index <= next {
+                index < home && home <= next
+            } else {
+                index < home || home <= next
+            };
+            if !between {
+                self.keys[index] = self.keys[next];
+                self.values[index] = self.values[next];
+                self.keys[next] = 0;
+                index = next;
+            }
+            next = (next + 1) % N;
+        }
+        value
+    }
+
+    // (key, value) of every entry
+    pub fn iter(&self) -> impl Iterator<Item = (usize, V)> + '_ {
+        self.keys.iter().zip(self.values.iter())
+            .filter(|(&key, _)| key != 0)
+            .map(|(&key, &value)| (key, value))
+    }
+}
+
+
+#[test_case]
+fn test_entries_are_found_after_removal() {
+    let mut table = AddressTable::<usize, 512>::new(0);
+    // enough entries that some of them collide
+    for key in (1..=200).map(|i| i * 0x1000) {
+        assert!(table.insert(key, key / 0x1000));
+    }
+    for key in (1..=200).step_by(2).map(|i| i * 0x1000) {
+        let index = table.find(key).expect("entry not found");
+        assert_eq!(table.remove(index), key / 0x1000);
+    }
+    for i in 1..=200 {
+        assert_eq!(table.find(i * 0x1000).map(|index| table.get(index)), Some(i).filter(|i| i % 2 == 0));
+    }
+    assert_eq!(table.len(), 100);
+    assert_eq!(table.iter().count(), 100);
+}
+
+#[test_case]
+fn test_full_table_rejects_inserts() {
+    let mut table = AddressTable::<(), 8>::new(());
+    for key in 1..8 {
+        assert!(table.insert(key * 16, ()));
+    }
+    assert!(!table.insert(0x1000, ()));
+    assert_eq!(table.find(0x1000), None);
+}
@@ -4,6 +4,11 @@ use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector
 use lazy_static::lazy_static;
 use x86_64::instructions::tables::load_tss;
 use x86_64::instructions::segmentation::{CS, Segment};
+use x86_64::instructions::interrupts;
+use core::cell::UnsafeCell;
+use spin::Once;
+
+use crate::memory::{self, KernelStack};
 
 
 
@@ -13,7 +18,15 @@ struct Selectors {
 } 
 
 pub fn init(){
+    // until the memory subsystem is up, double faults use a static stack without a guard page
+    unsafe {
+        (*TSS.0.get()).interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+            static mut STACK: [u8; BOOT_STACK_SIZE] = [0; BOOT_STACK_SIZE];
 
+            let stack_start = VirtAddr::from_ptr(&raw const STACK);
+            stack_start + BOOT_STACK_SIZE
+        };
+    }
 
     GDT.0.load();
     unsafe {
@@ -26,7 +39,8 @@ lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        // the tss is a static that is never moved, its interrupt stacks are replaced at runtime
+        let tss_selector = gdt.add_entry(unsafe {Descriptor::tss_segment_unchecked(TSS.0.get())});
         (gdt, Selectors { code_selector, tss_selector})
     };
 }
@@ -34,18 +48,32 @@ lazy_static! {
 
 pub const DOUBLE_FAULT_IST_INDEX:u16 = 0;
 
-lazy_static!{
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE :usize = 4096 *5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+// usable pages of every interrupt stack, the guarded stacks get an unmapped page below them
+const INTERRUPT_STACK_PAGES: usize = 5;
+const BOOT_STACK_SIZE: usize = 4096 * INTERRUPT_STACK_PAGES;
 
-            let stack_start = VirtAddr::from_ptr(&raw const STACK);
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
+// the tss is written by `init` and `init_interrupt_stacks`, the cpu reads it on every interrupt
+struct Tss(UnsafeCell<TaskStateSegment>);
+
+unsafe impl Sync for Tss {}
+
+static TSS: Tss = Tss(UnsafeCell::new(TaskStateSegment::new()));
+
+// the guarded interrupt stacks are kept here, so they are never freed
+static DOUBLE_FAULT_STACK: Once<KernelStack> = Once::new();
+
+// replaces the static interrupt stacks with guarded stacks from `memory::alloc_kernel_stack`
+// called by `memory::init_kernel_memory`
+pub fn init_interrupt_stacks() {
+    let stack = DOUBLE_FAULT_STACK.call_once(|| {
+        memory::alloc_kernel_stack(INTERRUPT_STACK_PAGES).expect("allocating the double fault stack failed")
+    });
+    interrupts::without_interrupts(|| unsafe {
+        (*TSS.0.get()).interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack.top();
+    });
 }
 
+// the stack used by the double fault handler, `None` until `init_interrupt_stacks` was called
+pub fn double_fault_stack() -> Option<&'static KernelStack> {
+    DOUBLE_FAULT_STACK.r#try()
+}
@@ -1,95 +1,70 @@
+use core::sync::atomic::{AtomicU8, Ordering};
 use x86_64::instructions::port::Port;
-use x86_64::registers::control::Cr2;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::idt::InterruptDescriptorTable;
 
 use spin;
 use pic8259::ChainedPics;
 use lazy_static::lazy_static;
 
+pub mod apic;
+pub mod exceptions;
+pub mod irq;
 
-use crate::gdt;
-use crate::{print, println, hlt_loop};
+pub use irq::{register_irq, unregister_irq, irq_count, spurious_irqs, IrqError, IrqHandler, LAPIC_TIMER_IRQ};
 
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
-pub enum InterruptIndex {
-    Timer = PIC_1_OFFSET,
-    Keyboard,
-}
-
-impl InterruptIndex {
-    fn as_u8(self) -> u8 {
-        self as u8
-    }
-
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
-    }
-}
+pub const TIMER_IRQ: u8 = 0;
+pub const KEYBOARD_IRQ: u8 = 1;
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        unsafe {
-            idt
-            .double_fault
-            .set_handler_fn(double_fault_handler)
-            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-        }
-        idt[InterruptIndex::Timer.as_usize()]
-            .set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()]
-            .set_handler_fn(keyboard_interrupt_handler);
-
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        exceptions::set_handlers(&mut idt);
+        irq::set_handlers(&mut idt);
         idt
     };
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(
-    _stack_frame: InterruptStackFrame) {
-        print!(".");
-        unsafe {
-            PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8())
-        }
-}
+// the line the timer handler is registered on, it moves with the timer source
+static TIMER_LINE: AtomicU8 = AtomicU8::new(TIMER_IRQ);
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(
-    _stack_frame: InterruptStackFrame) {
-        
-        let mut port = Port::new(0x60);
-        let scancode: u8 = unsafe{port.read()};
-        crate::task::keyboard::add_scancode(scancode);
-        unsafe {
-            PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8())
-        }
+pub fn timer_irq() -> u8 {
+    TIMER_LINE.load(Ordering::Relaxed)
 }
 
-pub fn init_idt() {
-    IDT.load();
+// moves the timer handler to `irq`, the previous line is masked
+pub fn route_timer(irq: u8) -> Result<(), IrqError> {
+    let previous = timer_irq();
+    if previous == irq {
+        return Ok(());
+    }
+    register_irq(irq, timer_interrupt)?;
+    unregister_irq(previous)?;
+    TIMER_LINE.store(irq, Ordering::Relaxed);
+    Ok(())
 }
 
-extern "x86-interrupt" fn breakpoint_handler(
-    stack_frame: InterruptStackFrame){
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+fn timer_interrupt() {
+    crate::time::tick();
+    crate::task::timer::wake_expired();
 }
 
-extern "x86-interrupt" fn  double_fault_handler(
-    stack_frame: InterruptStackFrame,
-     _error_code: u64) -> !{
-        panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+fn keyboard_interrupt() {
+    let mut port = Port::new(0x60);
+    let scancode: u8 = unsafe{port.read()};
+    crate::task::keyboard::add_scancode(scancode);
 }
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode ) {
-        println!("Exception: PAGE FAULT");
-        println!("Accessed Address: {:?}", Cr2::read());
-        println!("Error Code: {error_code:?}");
-        println!("{stack_frame:#?}");
+pub fn init_idt() {
+    IDT.load();
+}
 
-        hlt_loop();
+// remaps the PICs behind the exception vectors, only lines with a handler are unmasked
+// `apic::init` takes over once the kernel memory is up
+pub fn init_pics() {
+    unsafe {PICS.lock().initialize()};
+    irq::init_masks();
+    register_irq(TIMER_IRQ, timer_interrupt).expect("timer irq is already registered");
+    register_irq(KEYBOARD_IRQ, keyboard_interrupt).expect("keyboard irq is already registered");
 }
 
 #[test_case]
@@ -0,0 +1,291 @@
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::VirtAddr;
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::PageTableFlags;
+use spin::{Mutex, Once};
+
+use super::{irq, PICS};
+use crate::acpi::{self, IsaRoute, Polarity, TriggerMode, ISA_IRQS, MAX_IO_APICS};
+use crate::memory::vmm::{self, RegionKind, VmmError};
+use crate::serial_println;
+use crate::time::{self, pit, TimerSource};
+
+// vectors of the interrupts the local APIC raises itself, above the ISA IRQs
+pub const TIMER_VECTOR: u8 = super::PIC_2_OFFSET + 8;
+// the low four bits of the spurious vector are hardwired to one on older APICs
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// local APIC registers, offsets from its base
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TASK_PRIORITY: usize = 0x80;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SPURIOUS: usize = 0xf0;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_TIMER_INITIAL: usize = 0x380;
+const LAPIC_TIMER_CURRENT: usize = 0x390;
+const LAPIC_TIMER_DIVIDE: usize = 0x3e0;
+
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+// the timer counts down at the bus frequency divided by 16
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+// IO-APIC registers, selected through IOREGSEL and accessed through IOWIN
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECTION_LEVEL: u32 = 1 << 15;
+const REDIRECTION_MASKED: u32 = 1 << 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicError {
+    // the kernel was built with the `legacy-pic` feature
+    Disabled,
+    NotSupported,
+    NoMadt,
+    NoIoApic,
+    Map(VmmError),
+}
+
+impl From<VmmError> for ApicError {
+    fn from(error: VmmError) -> Self {
+        ApicError::Map(error)
+    }
+}
+
+struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    fn read(&mut self, register: u32) -> u32 {
+        unsafe {
+            (self.base + IOREGSEL as u64).as_mut_ptr::<u32>().write_volatile(register);
+            (self.base + IOWIN as u64).as_ptr::<u32>().read_volatile()
+        }
+    }
+
+    fn write(&mut self, register: u32, value: u32) {
+        unsafe {
+            (self.base + IOREGSEL as u64).as_mut_ptr::<u32>().write_volatile(register);
+            (self.base + IOWIN as u64).as_mut_ptr::<u32>().write_volatile(value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.entries).contains(&gsi)
+    }
+
+    // low and high half of the redirection entry of `gsi`
+    fn registers(&self, gsi: u32) -> (u32, u32) {
+        let low = IOAPIC_REDIRECTION_TABLE + 2 * (gsi - self.gsi_base);
+        (low, low + 1)
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static LOCAL_APIC: Once<VirtAddr> = Once::new();
+static IO_APICS: Mutex<[Option<IoApic>; MAX_IO_APICS]> = Mutex::new([const { None }; MAX_IO_APICS]);
+// `None` for ISA IRQs whose GSI is taken by an interrupt source override of another IRQ
+static ISA_ROUTES: Once<[Option<IsaRoute>; ISA_IRQS]> = Once::new();
+static TIMER_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+// interrupts go through the local and IO-APICs instead of the 8259 PICs
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// cpuid 1 reports an on chip local APIC in edx bit 9
+pub fn is_supported() -> bool {
+    __cpuid(1).edx & (1 << 9) != 0
+}
+
+// ticks per second of the local APIC timer after the divider, 0 until the APIC is enabled
+pub fn timer_frequency() -> u64 {
+    TIMER_FREQUENCY.load(Ordering::Relaxed)
+}
+
+pub fn isa_route(irq: u8) -> Option<IsaRoute> {
+    ISA_ROUTES.r#try()?.get(irq as usize).copied().flatten()
+}
+
+fn local_apic_read(register: usize) -> u32 {
+    let base = LOCAL_APIC.r#try().expect("the local apic is not mapped");
+    unsafe {(*base + register as u64).as_ptr::<u32>().read_volatile()}
+}
+
+fn local_apic_write(register: usize, value: u32) {
+    let base = LOCAL_APIC.r#try().expect("the local apic is not mapped");
+    unsafe {(*base + register as u64).as_mut_ptr::<u32>().write_volatile(value)};
+}
+
+pub(super) fn end_of_interrupt() {
+    local_apic_write(LAPIC_EOI, 0);
+}
+
+// masks or unmasks the redirection entry of an ISA IRQ, IRQs without a route are ignored
+pub(super) fn set_masked(irq: u8, masked: bool) {
+    let Some(route) = isa_route(irq) else {
+        return;
+    };
+    let mut io_apics = IO_APICS.lock();
+    if let Some(io_apic) = io_apics.iter_mut().flatten().find(|io_apic| io_apic.handles(route.gsi)) {
+        let (low, _) = io_apic.registers(route.gsi);
+        let entry = io_apic.read(low);
+        io_apic.write(low, if masked { entry | REDIRECTION_MASKED } else { entry & !REDIRECTION_MASKED });
+    }
+}
+
+// an ISA IRQ keeps its identity mapping unless another IRQ was redirected to its GSI
+fn isa_routes(madt: &acpi::Madt) -> [Option<IsaRoute>; ISA_IRQS] {
+    core::array::from_fn(|irq| {
+        let route = madt.isa_routes[irq];
+        let taken = madt.isa_routes.iter().enumerate()
+            .any(|(other, other_route)| other != irq && other_route.gsi == route.gsi);
+        (!taken || route.gsi != irq as u32).then_some(route)
+    })
+}
+
+// switches from the 8259 PICs to the APICs
+// the PICs are masked, the ISA IRQs are routed through the IO-APICs to the same vectors and
+// the local APIC timer replaces the PIT as the timer source; on error the PICs stay in charge
+// needs the kernel memory to map the APIC registers
+pub fn init() -> Result<(), ApicError> {
+    let result = enable();
+    match result {
+        Ok(()) => {
+            serial_println!("apic: enabled, local apic timer at {} ticks per second", timer_frequency());
+            if let Err(error) = time::set_timer_source(TimerSource::LocalApic) {
+                serial_println!("apic: {:?}, the PIT stays the timer source", error);
+            }
+        }
+        Err(error) => {
+            serial_println!("apic: {:?}, using the 8259 PICs", error);
+        }
+    }
+    result
+}
+
+fn enable() -> Result<(), ApicError> {
+    if cfg!(feature = "legacy-pic") {
+        return Err(ApicError::Disabled);
+    }
+    if !is_supported() {
+        return Err(ApicError::NotSupported);
+    }
+    let madt = acpi::madt().ok_or(ApicError::NoMadt)?;
+    if madt.io_apics().next().is_none() {
+        return Err(ApicError::NoIoApic);
+    }
+
+    let flags = PageTableFlags::WRITABLE;
+    let local_apic = vmm::map_physical("local apic", RegionKind::Mmio, madt.local_apic, 4096, flags)?;
+    let mut io_apics: [Option<IoApic>; MAX_IO_APICS] = [const { None }; MAX_IO_APICS];
+    for (index, entry) in madt.io_apics().take(MAX_IO_APICS).enumerate() {
+        let region = match vmm::map_physical("io apic", RegionKind::Mmio, entry.address, 4096, flags) {
+            Ok(region) => region,
+            Err(error) => {
+                // a later call maps everything again
+                for io_apic in io_apics.iter().flatten() {
+                    let _ = vmm::release(io_apic.base);
+                }
+                let _ = vmm::release(local_apic.start);
+                return Err(error.into());
+            }
+        };
+        let mut io_apic = IoApic { base: region.start, gsi_base: entry.gsi_base, entries: 0 };
+        io_apic.entries = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xff) + 1;
+        io_apics[index] = Some(io_apic);
+    }
+
+    LOCAL_APIC.call_once(|| local_apic.start);
+    let routes = *ISA_ROUTES.call_once(|| isa_routes(&madt));
+    let apic_id = local_apic_read(LAPIC_ID) >> 24;
+
+    interrupts::without_interrupts(|| {
+        let mut apic_base = Msr::new(IA32_APIC_BASE);
+        unsafe {apic_base.write(apic_base.read() | APIC_BASE_ENABLE)};
+        local_apic_write(LAPIC_TASK_PRIORITY, 0);
+        local_apic_write(LAPIC_SPURIOUS, SPURIOUS_APIC_ENABLE | u32::from(SPURIOUS_VECTOR));
+
+        // every input starts masked, ISA IRQs are pointed at the vectors they had on the PICs
+        for io_apic in io_apics.iter_mut().flatten() {
+            for gsi in io_apic.gsi_base..io_apic.gsi_base + io_apic.entries {
+                let (low, _) = io_apic.registers(gsi);
+                io_apic.write(low, REDIRECTION_MASKED);
+            }
+        }
+        for (irq, route) in routes.iter().enumerate() {
+            let Some(route) = route else {
+                continue;
+            };
+            if let Some(io_apic) = io_apics.iter_mut().flatten().find(|io_apic| io_apic.handles(route.gsi)) {
+                let (low, high) = io_apic.registers(route.gsi);
+                let mut entry = REDIRECTION_MASKED | u32::from(irq::irq_vector(irq as u8));
+                if route.polarity == Polarity::ActiveLow {
+                    entry |= REDIRECTION_ACTIVE_LOW;
+                }
+                if route.trigger == TriggerMode::Level {
+                    entry |= REDIRECTION_LEVEL;
+                }
+                io_apic.write(high, apic_id << 24);
+                io_apic.write(low, entry);
+            }
+        }
+        *IO_APICS.lock() = io_apics;
+
+        // the PICs stay remapped, so a spurious interrupt they might still raise lands on an irq vector
+        if madt.has_legacy_pics {
+            unsafe {PICS.lock().disable()};
+        }
+        ENABLED.store(true, Ordering::Relaxed);
+        irq::unmask_registered();
+
+        calibrate_timer();
+    });
+    Ok(())
+}
+
+// the local APIC timer runs at an unknown bus frequency, it is measured against 10 ms of the PIT
+// and stays masked until `start_timer`
+fn calibrate_timer() {
+    local_apic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    local_apic_write(LAPIC_LVT_TIMER, LVT_MASKED);
+    local_apic_write(LAPIC_TIMER_INITIAL, u32::MAX);
+    pit::busy_wait(Duration::from_millis(10));
+    let elapsed = u32::MAX - local_apic_read(LAPIC_TIMER_CURRENT);
+    local_apic_write(LAPIC_TIMER_INITIAL, 0);
+    TIMER_FREQUENCY.store(u64::from(elapsed) * 100, Ordering::Relaxed);
+}
+
+// raises `LAPIC_TIMER_IRQ` `frequency` times per second, returns the period it really runs with
+// `None` until the APIC is enabled
+pub(crate) fn start_timer(frequency: u32) -> Option<Duration> {
+    let ticks_per_second = timer_frequency();
+    if !is_enabled() || ticks_per_second == 0 {
+        return None;
+    }
+    let initial = (ticks_per_second / u64::from(frequency.max(1))).clamp(1, u64::from(u32::MAX));
+    local_apic_write(LAPIC_LVT_TIMER, LVT_TIMER_PERIODIC | u32::from(TIMER_VECTOR));
+    local_apic_write(LAPIC_TIMER_INITIAL, initial as u32);
+    Some(Duration::from_nanos(initial * 1_000_000_000 / ticks_per_second))
+}
+
+pub(crate) fn stop_timer() {
+    if LOCAL_APIC.r#try().is_some() {
+        local_apic_write(LAPIC_LVT_TIMER, LVT_MASKED);
+        local_apic_write(LAPIC_TIMER_INITIAL, 0);
+    }
+}
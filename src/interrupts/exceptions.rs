@@ -0,0 +1,314 @@
+use core::arch::naked_asm;
+use core::fmt::{self, Write};
+use x86_64::VirtAddr;
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::registers::model_specific::Efer;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+
+use crate::{gdt, memory, println};
+use crate::serial::SERIAL1;
+use crate::vga_buffer::WRITER;
+
+// general purpose registers of the interrupted code
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "rax {:#018x} rbx {:#018x} rcx {:#018x}", self.rax, self.rbx, self.rcx)?;
+        writeln!(f, "rdx {:#018x} rsi {:#018x} rdi {:#018x}", self.rdx, self.rsi, self.rdi)?;
+        writeln!(f, "rbp {:#018x} r8  {:#018x} r9  {:#018x}", self.rbp, self.r8, self.r9)?;
+        writeln!(f, "r10 {:#018x} r11 {:#018x} r12 {:#018x}", self.r10, self.r11, self.r12)?;
+        write!(f, "r13 {:#018x} r14 {:#018x} r15 {:#018x}", self.r13, self.r14, self.r15)
+    }
+}
+
+// what the entry stubs leave on the stack of the handler, the registers pushed by the stub
+// in front of the error code and the frame pushed by the cpu
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub registers: Registers,
+    // 0 for exceptions without an error code
+    pub error_code: u64,
+    pub stack_frame: InterruptStackFrame,
+}
+
+// registers of the exception that ended in `fatal`, for the panic handler
+// only written by `fatal`, which never returns
+static mut FATAL_REGISTERS: Registers = Registers {
+    rax: 0, rbx: 0, rcx: 0, rdx: 0, rsi: 0, rdi: 0, rbp: 0,
+    r8: 0, r9: 0, r10: 0, r11: 0, r12: 0, r13: 0, r14: 0, r15: 0,
+};
+
+// entry stub that saves the registers of the interrupted code on the stack and calls
+// `$handler` with a pointer to the `ExceptionFrame`, the registers are restored if it returns
+// the compiler may use registers before the first statement of a handler, the stub runs before that
+macro_rules! saving_registers {
+    ($stub:ident, $handler:ident) => {
+        // the cpu pushed no error code, a zero keeps the layout of the frame the same
+        saving_registers!(@stub $stub, $handler, "push 0");
+    };
+    ($stub:ident, $handler:ident, error_code) => {
+        saving_registers!(@stub $stub, $handler);
+    };
+    (@stub $stub:ident, $handler:ident $(, $prefix:literal)?) => {
+        #[unsafe(naked)]
+        extern "C" fn $stub() {
+            naked_asm!(
+                $($prefix,)?
+                "push r15",
+                "push r14",
+                "push r13",
+                "push r12",
+                "push r11",
+                "push r10",
+                "push r9",
+                "push r8",
+                "push rbp",
+                "push rdi",
+                "push rsi",
+                "push rdx",
+                "push rcx",
+                "push rbx",
+                "push rax",
+                "mov rdi, rsp",
+                "cld",
+                // the cpu aligned the stack to 16 bytes before the frame, 16 qwords were pushed since
+                "sub rsp, 8",
+                "call {handler}",
+                "add rsp, 8",
+                "pop rax",
+                "pop rbx",
+                "pop rcx",
+                "pop rdx",
+                "pop rsi",
+                "pop rdi",
+                "pop rbp",
+                "pop r8",
+                "pop r9",
+                "pop r10",
+                "pop r11",
+                "pop r12",
+                "pop r13",
+                "pop r14",
+                "pop r15",
+                // drops the error code
+                "add rsp, 8",
+                "iretq",
+                handler = sym $handler,
+            );
+        }
+    };
+}
+
+pub fn saved_registers() -> Registers {
+    unsafe {(&raw const FATAL_REGISTERS).read()}
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ControlRegisters {
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub efer: u64,
+}
+
+impl ControlRegisters {
+    pub fn read() -> Self {
+        ControlRegisters {
+            cr0: Cr0::read_raw(),
+            cr2: Cr2::read_raw(),
+            cr3: Cr3::read_raw().0.start_address().as_u64() | u64::from(Cr3::read_raw().1),
+            cr4: Cr4::read_raw(),
+            efer: Efer::read_raw(),
+        }
+    }
+}
+
+impl fmt::Display for ControlRegisters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "cr0 {:#018x} cr2 {:#018x} cr3 {:#018x}", self.cr0, self.cr2, self.cr3)?;
+        write!(f, "cr4 {:#018x} efer {:#017x}", self.cr4, self.efer)
+    }
+}
+
+// error code pushed by the cpu, decoded by the kind of the exception
+#[derive(Debug, Clone, Copy)]
+pub enum ErrorCode {
+    None,
+    // the segment selector that caused the exception, 0 if it wasn't caused by a selector
+    Selector(u64),
+    PageFault(PageFaultErrorCode),
+    ControlProtection(u64),
+    // no decoding, e.g. always 0
+    Raw(u64),
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErrorCode::None => write!(f, "none"),
+            ErrorCode::Selector(0) => write!(f, "0 (not caused by a selector)"),
+            ErrorCode::Selector(code) => {
+                let table = match (code >> 1) & 0b11 {
+                    0b00 => "GDT",
+                    0b10 => "LDT",
+                    _ => "IDT",
+                };
+                write!(f, "{:#x} ({} index {}", code, table, (code >> 3) & 0x1fff)?;
+                if code & 1 != 0 {
+                    write!(f, ", external event")?;
+                }
+                write!(f, ")")
+            }
+            ErrorCode::PageFault(code) => write!(f, "{:#x} ({:?})", code.bits(), code),
+            ErrorCode::ControlProtection(code) => {
+                let cause = match code & 0x7fff {
+                    1 => "near ret",
+                    2 => "far ret or iret",
+                    3 => "missing endbranch",
+                    4 => "rstorssp",
+                    5 => "setssbsy",
+                    _ => "unknown",
+                };
+                write!(f, "{:#x} ({})", code, cause)
+            }
+            ErrorCode::Raw(code) => write!(f, "{:#x}", code),
+        }
+    }
+}
+
+// the interrupted code may hold the output locks, it never runs again once the exception is fatal
+fn unlock_output() {
+    if SERIAL1.try_lock().is_none() {
+        unsafe {SERIAL1.force_unlock()};
+    }
+    // for the panic handler, which prints to the screen
+    if WRITER.try_lock().is_none() {
+        unsafe {WRITER.force_unlock()};
+    }
+}
+
+// every exception that can't be resolved ends here
+// the state is dumped to serial once, the panic message carries the name of the exception
+pub fn fatal(name: &str, vector: u8, error_code: ErrorCode, frame: &ExceptionFrame) -> ! {
+    unsafe {(&raw mut FATAL_REGISTERS).write(frame.registers)};
+    let control = ControlRegisters::read();
+
+    unlock_output();
+    let _ = writeln!(
+        SERIAL1.lock(),
+        "EXCEPTION: {} (vector {})\nerror code: {}\n{:#?}\n{}\n{}",
+        name, vector, error_code, frame.stack_frame, frame.registers, control
+    );
+
+    panic!("EXCEPTION: {}", name);
+}
+
+macro_rules! fatal_handler {
+    ($stub:ident, $handler:ident, $name:expr, $vector:expr) => {
+        extern "C" fn $handler(frame: &ExceptionFrame) -> ! {
+            fatal($name, $vector, ErrorCode::None, frame);
+        }
+        saving_registers!($stub, $handler);
+    };
+    ($stub:ident, $handler:ident, $name:expr, $vector:expr, $error_code:path) => {
+        extern "C" fn $handler(frame: &ExceptionFrame) -> ! {
+            fatal($name, $vector, $error_code(frame.error_code), frame);
+        }
+        saving_registers!($stub, $handler, error_code);
+    };
+}
+
+fatal_handler!(divide_error_entry, divide_error_handler, "DIVIDE ERROR", 0);
+fatal_handler!(non_maskable_interrupt_entry, non_maskable_interrupt_handler, "NON MASKABLE INTERRUPT", 2);
+fatal_handler!(overflow_entry, overflow_handler, "OVERFLOW", 4);
+fatal_handler!(bound_range_exceeded_entry, bound_range_exceeded_handler, "BOUND RANGE EXCEEDED", 5);
+fatal_handler!(invalid_opcode_entry, invalid_opcode_handler, "INVALID OPCODE", 6);
+fatal_handler!(device_not_available_entry, device_not_available_handler, "DEVICE NOT AVAILABLE", 7);
+fatal_handler!(double_fault_entry, double_fault_handler, "DOUBLE FAULT", 8, ErrorCode::Raw);
+fatal_handler!(invalid_tss_entry, invalid_tss_handler, "INVALID TSS", 10, ErrorCode::Selector);
+fatal_handler!(segment_not_present_entry, segment_not_present_handler, "SEGMENT NOT PRESENT", 11, ErrorCode::Selector);
+fatal_handler!(stack_segment_fault_entry, stack_segment_fault_handler, "STACK SEGMENT FAULT", 12, ErrorCode::Selector);
+fatal_handler!(general_protection_fault_entry, general_protection_fault_handler, "GENERAL PROTECTION FAULT", 13, ErrorCode::Selector);
+fatal_handler!(x87_floating_point_entry, x87_floating_point_handler, "X87 FLOATING POINT", 16);
+fatal_handler!(alignment_check_entry, alignment_check_handler, "ALIGNMENT CHECK", 17, ErrorCode::Raw);
+fatal_handler!(machine_check_entry, machine_check_handler, "MACHINE CHECK", 18);
+fatal_handler!(simd_floating_point_entry, simd_floating_point_handler, "SIMD FLOATING POINT", 19);
+fatal_handler!(virtualization_entry, virtualization_handler, "VIRTUALIZATION", 20);
+fatal_handler!(cp_protection_entry, cp_protection_handler, "CONTROL PROTECTION", 21, ErrorCode::ControlProtection);
+fatal_handler!(hv_injection_entry, hv_injection_handler, "HYPERVISOR INJECTION", 28);
+fatal_handler!(vmm_communication_entry, vmm_communication_handler, "VMM COMMUNICATION", 29, ErrorCode::Raw);
+fatal_handler!(security_exception_entry, security_exception_handler, "SECURITY EXCEPTION", 30, ErrorCode::Raw);
+
+extern "x86-interrupt" fn debug_handler(
+    stack_frame: InterruptStackFrame) {
+        println!("EXCEPTION: DEBUG\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn breakpoint_handler(
+    stack_frame: InterruptStackFrame){
+    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+}
+
+// returns to the faulting instruction when the fault was resolved
+extern "C" fn page_fault_handler(frame: &ExceptionFrame) {
+    let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+    // first touch of a lazy region, execution resumes once the page is mapped
+    if memory::vmm::handle_page_fault(Cr2::read(), error_code) {
+        return;
+    }
+    fatal("PAGE FAULT", 14, ErrorCode::PageFault(error_code), frame);
+}
+saving_registers!(page_fault_entry, page_fault_handler, error_code);
+
+// installs a handler for every exception vector, the fatal ones behind their entry stubs
+pub(super) fn set_handlers(idt: &mut InterruptDescriptorTable) {
+    let entry = |stub: extern "C" fn()| VirtAddr::new(stub as usize as u64);
+    idt.debug.set_handler_fn(debug_handler);
+    idt.breakpoint.set_handler_fn(breakpoint_handler);
+    unsafe {
+        idt.divide_error.set_handler_addr(entry(divide_error_entry));
+        idt.non_maskable_interrupt.set_handler_addr(entry(non_maskable_interrupt_entry));
+        idt.overflow.set_handler_addr(entry(overflow_entry));
+        idt.bound_range_exceeded.set_handler_addr(entry(bound_range_exceeded_entry));
+        idt.invalid_opcode.set_handler_addr(entry(invalid_opcode_entry));
+        idt.device_not_available.set_handler_addr(entry(device_not_available_entry));
+        idt
+        .double_fault
+        .set_handler_addr(entry(double_fault_entry))
+        .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        idt.invalid_tss.set_handler_addr(entry(invalid_tss_entry));
+        idt.segment_not_present.set_handler_addr(entry(segment_not_present_entry));
+        idt.stack_segment_fault.set_handler_addr(entry(stack_segment_fault_entry));
+        idt.general_protection_fault.set_handler_addr(entry(general_protection_fault_entry));
+        idt.page_fault.set_handler_addr(entry(page_fault_entry));
+        idt.x87_floating_point.set_handler_addr(entry(x87_floating_point_entry));
+        idt.alignment_check.set_handler_addr(entry(alignment_check_entry));
+        idt.machine_check.set_handler_addr(entry(machine_check_entry));
+        idt.simd_floating_point.set_handler_addr(entry(simd_floating_point_entry));
+        idt.virtualization.set_handler_addr(entry(virtualization_entry));
+        idt.cp_protection_exception.set_handler_addr(entry(cp_protection_entry));
+        idt.hv_injection_exception.set_handler_addr(entry(hv_injection_entry));
+        idt.vmm_communication_exception.set_handler_addr(entry(vmm_communication_entry));
+        idt.security_exception.set_handler_addr(entry(security_exception_entry));
+    }
+}
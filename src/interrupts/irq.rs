@@ -0,0 +1,229 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::{interrupts, port::Port};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use spin::Mutex;
+
+use super::{apic, PICS, PIC_1_OFFSET, PIC_2_OFFSET};
+
+// lines of the two chained 8259 PICs
+pub const IRQ_LINES: usize = 16;
+// interrupts of the local APIC get the numbers after the ISA IRQs
+pub const LAPIC_TIMER_IRQ: u8 = IRQ_LINES as u8;
+pub const IRQS: usize = IRQ_LINES + 1;
+// the slave PIC is connected to this line of the master
+const CASCADE_IRQ: u8 = 2;
+// a spurious interrupt shows up as the lowest priority line of a PIC
+const MASTER_SPURIOUS_IRQ: u8 = 7;
+const SLAVE_SPURIOUS_IRQ: u8 = 15;
+
+// called by the dispatcher in interrupt context, the EOI is sent after it returns
+pub type IrqHandler = fn();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    InvalidIrq,
+    AlreadyRegistered,
+    NotRegistered,
+}
+
+static HANDLERS: Mutex<[Option<IrqHandler>; IRQS]> = Mutex::new([None; IRQS]);
+static COUNTS: [AtomicU64; IRQS] = [const { AtomicU64::new(0) }; IRQS];
+static SPURIOUS: AtomicU64 = AtomicU64::new(0);
+
+// installs `handler` for `irq` and unmasks the line
+pub fn register_irq(irq: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    check_irq(irq)?;
+    interrupts::without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let slot = &mut handlers[irq as usize];
+        if slot.is_some() {
+            return Err(IrqError::AlreadyRegistered);
+        }
+        *slot = Some(handler);
+        set_masked(irq, false);
+        Ok(())
+    })
+}
+
+// masks the line and removes its handler, returns the handler
+pub fn unregister_irq(irq: u8) -> Result<IrqHandler, IrqError> {
+    check_irq(irq)?;
+    interrupts::without_interrupts(|| {
+        let handler = HANDLERS.lock()[irq as usize].take().ok_or(IrqError::NotRegistered)?;
+        set_masked(irq, true);
+        Ok(handler)
+    })
+}
+
+// interrupts delivered on `irq` since boot, spurious ones are not counted
+pub fn irq_count(irq: u8) -> u64 {
+    COUNTS.get(irq as usize).map_or(0, |count| count.load(Ordering::Relaxed))
+}
+
+// spurious interrupts on IRQ 7 and 15 and on the spurious vector of the local APIC since boot
+pub fn spurious_irqs() -> u64 {
+    SPURIOUS.load(Ordering::Relaxed)
+}
+
+fn check_irq(irq: u8) -> Result<(), IrqError> {
+    // the cascade line only forwards the interrupts of the slave
+    if irq as usize >= IRQS || irq == CASCADE_IRQ {
+        return Err(IrqError::InvalidIrq);
+    }
+    Ok(())
+}
+
+fn set_masked(irq: u8, masked: bool) {
+    if apic::is_enabled() {
+        apic::set_masked(irq, masked);
+        return;
+    }
+    if irq as usize >= IRQ_LINES {
+        return;
+    }
+    let mut pics = PICS.lock();
+    let mut masks = unsafe {pics.read_masks()};
+    let (pic, bit) = ((irq / 8) as usize, 1 << (irq % 8));
+    if masked {
+        masks[pic] |= bit;
+    } else {
+        masks[pic] &= !bit;
+    }
+    unsafe {pics.write_masks(masks[0], masks[1])};
+}
+
+// masks every line except the cascade, `register_irq` unmasks the lines that have a handler
+pub(super) fn init_masks() {
+    let mut pics = PICS.lock();
+    unsafe {pics.write_masks(!(1 << CASCADE_IRQ), 0xff)};
+}
+
+// unmasks the lines that have a handler, after the switch to the IO-APIC
+pub(super) fn unmask_registered() {
+    let handlers = HANDLERS.lock();
+    for (irq, handler) in handlers.iter().enumerate() {
+        if handler.is_some() {
+            set_masked(irq as u8, false);
+        }
+    }
+}
+
+// the in service register tells a real interrupt from a spurious one
+fn in_service(irq: u8) -> bool {
+    let command = if irq < 8 { 0x20 } else { 0xa0 };
+    let mut port: Port<u8> = Port::new(command);
+    unsafe {
+        // OCW3, read the in service register on the next read
+        port.write(0x0b);
+        port.read() & (1 << (irq % 8)) != 0
+    }
+}
+
+fn dispatch(irq: u8) {
+    if apic::is_enabled() {
+        COUNTS[irq as usize].fetch_add(1, Ordering::Relaxed);
+        let handler = HANDLERS.lock()[irq as usize];
+        if let Some(handler) = handler {
+            handler();
+        }
+        apic::end_of_interrupt();
+        return;
+    }
+
+    if irq == MASTER_SPURIOUS_IRQ && !in_service(irq) {
+        // no EOI, the master didn't raise an interrupt
+        SPURIOUS.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    if irq == SLAVE_SPURIOUS_IRQ && !in_service(irq) {
+        // the master saw a real interrupt on the cascade line, only it gets an EOI
+        SPURIOUS.fetch_add(1, Ordering::Relaxed);
+        unsafe {PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + CASCADE_IRQ)};
+        return;
+    }
+
+    COUNTS[irq as usize].fetch_add(1, Ordering::Relaxed);
+    // the lock is released before the call, a handler may unregister itself
+    let handler = HANDLERS.lock()[irq as usize];
+    if let Some(handler) = handler {
+        handler();
+    }
+    unsafe {PICS.lock().notify_end_of_interrupt(irq_vector(irq))};
+}
+
+pub(super) fn irq_vector(irq: u8) -> u8 {
+    match irq {
+        0..8 => PIC_1_OFFSET + irq,
+        8..16 => PIC_2_OFFSET + irq - 8,
+        _ => apic::TIMER_VECTOR,
+    }
+}
+
+macro_rules! irq_entries {
+    ($($entry:ident = $irq:expr),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $entry(_stack_frame: InterruptStackFrame) {
+                dispatch($irq);
+            }
+        )*
+
+        const ENTRIES: [extern "x86-interrupt" fn(InterruptStackFrame); IRQS] = [$($entry),*];
+    };
+}
+
+irq_entries!(
+    irq_0 = 0, irq_1 = 1, irq_2 = 2, irq_3 = 3, irq_4 = 4, irq_5 = 5, irq_6 = 6, irq_7 = 7,
+    irq_8 = 8, irq_9 = 9, irq_10 = 10, irq_11 = 11, irq_12 = 12, irq_13 = 13, irq_14 = 14, irq_15 = 15,
+    lapic_timer = LAPIC_TIMER_IRQ,
+);
+
+// the local APIC doesn't expect an EOI for a spurious interrupt
+extern "x86-interrupt" fn lapic_spurious(_stack_frame: InterruptStackFrame) {
+    SPURIOUS.fetch_add(1, Ordering::Relaxed);
+}
+
+// points the vectors of all PIC lines and local APIC interrupts to the dispatcher
+pub(super) fn set_handlers(idt: &mut InterruptDescriptorTable) {
+    for (irq, &entry) in ENTRIES.iter().enumerate() {
+        idt[irq_vector(irq as u8) as usize].set_handler_fn(entry);
+    }
+    idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(lapic_spurious);
+}
+
+#[test_case]
+fn registration_errors() {
+    fn handler() {}
+    assert_eq!(register_irq(IRQS as u8, handler), Err(IrqError::InvalidIrq));
+    assert_eq!(register_irq(CASCADE_IRQ, handler), Err(IrqError::InvalidIrq));
+    assert_eq!(register_irq(super::TIMER_IRQ, handler), Err(IrqError::AlreadyRegistered));
+    assert_eq!(unregister_irq(12).err(), Some(IrqError::NotRegistered));
+
+    register_irq(12, handler).unwrap();
+    assert!(unregister_irq(12).is_ok());
+    assert_eq!(unregister_irq(12).err(), Some(IrqError::NotRegistered));
+}
+
+#[test_case]
+fn dispatcher_calls_the_handler_and_counts() {
+    use core::sync::atomic::AtomicBool;
+    static CALLED: AtomicBool = AtomicBool::new(false);
+    fn handler() {
+        CALLED.store(true, Ordering::Relaxed);
+    }
+
+    register_irq(11, handler).unwrap();
+    let count = irq_count(11);
+    // raised in software, the PIC gets an EOI without an interrupt in service, which it ignores
+    unsafe {core::arch::asm!("int {vector}", vector = const PIC_2_OFFSET + 3)};
+    assert!(CALLED.load(Ordering::Relaxed));
+    assert_eq!(irq_count(11), count + 1);
+    unregister_irq(11).unwrap();
+}
+
+#[test_case]
+fn timer_interrupts_are_counted() {
+    let count = irq_count(super::TIMER_IRQ);
+    while irq_count(super::TIMER_IRQ) == count {
+        x86_64::instructions::hlt();
+    }
+}
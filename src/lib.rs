@@ -9,6 +9,7 @@
 use core::panic::PanicInfo;
 
 
+pub mod acpi;
 pub mod serial;
 pub mod vga_buffer;
 pub mod interrupts;
@@ -16,13 +17,15 @@ pub mod gdt;
 pub mod memory;
 pub mod allocator;
 pub mod task;
+pub mod time;
 
 extern crate alloc;
 
 pub fn init(){
     interrupts::init_idt();
     gdt::init();
-    unsafe {interrupts::PICS.lock().initialize()};
+    time::init();
+    interrupts::init_pics();
     x86_64::instructions::interrupts::enable();
 }
 
@@ -15,7 +15,7 @@ use rust_kernel::task::Task;
 use rust_kernel::task::executor::Executor;
 use core::panic::PanicInfo;
 use bootloader::{BootInfo, entry_point};
-use x86_64::{structures::paging::Translate, VirtAddr};
+use x86_64::VirtAddr;
 
 extern crate alloc;
 use alloc::{boxed::Box, vec, vec::Vec, rc::Rc};
@@ -26,6 +26,7 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     println!("Hello World :) \n");
     
     rust_kernel::init();
+    println!("booted at {} UTC", rust_kernel::time::date());
 
 
     // ------------------------------------------------------------------
@@ -33,34 +34,33 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     // ------------------------------------------------------------------
     
     let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe {rust_kernel::memory::init(physical_memory_offset)};
-    let mut frame_allocator = unsafe {
-        rust_kernel::memory::BootInfoFrameAllocator::init(&boot_info.memory_map)
+    let mapper = unsafe {rust_kernel::memory::init(physical_memory_offset)};
+    let frame_allocator = unsafe {
+        rust_kernel::memory::bitmap::BitmapFrameAllocator::init(&boot_info.memory_map, physical_memory_offset)
     };
 
     // ------------------------------------------------------------------
     // Paging Examples 
     // ------------------------------------------------------------------
 
-    println!("Paging Demo:");
-    let addresses = [
-        0xb8000,
-        0x0100_0020_1a10,
-        boot_info.physical_memory_offset,
-    ];
-
-    for &address in &addresses {
-        let virt = VirtAddr::new(address);
-        let phys = mapper.translate_addr(virt);
-        println!("{virt:?} -> {phys:?}");
-    }
+    // the walks are printed over serial
+    rust_kernel::memory::explain_translation(VirtAddr::new(0xb8000));
+    rust_kernel::memory::explain_translation(VirtAddr::new(boot_info.physical_memory_offset));
     
     // ------------------------------------------------------------------
     // initializing Heap 
     // ------------------------------------------------------------------
     
-    rust_kernel::allocator::init_heap(&mut mapper, &mut frame_allocator)
+    rust_kernel::memory::init_kernel_memory(mapper, frame_allocator);
+    rust_kernel::allocator::init_heap()
     .expect("heap initialization failed");
+    // the APIC registers are mapped through the vmm, the PICs stay in charge if it fails
+    let _ = rust_kernel::interrupts::apic::init();
+    // the HPET replaces the PIT as the reference of the TSC and, with `hpet-timer`, as the timer
+    let _ = rust_kernel::time::init_hpet();
+    let _ = rust_kernel::memory::buddy::init(rust_kernel::memory::buddy::POOL_FRAMES);
+    rust_kernel::memory::vmm::dump_layout();
+    rust_kernel::memory::dump_page_tables();
 
     // ------------------------------------------------------------------
     // Heap Examples 
@@ -84,6 +84,8 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     core::mem::drop(reference_counted);
     println!("reference count is {} now", Rc::strong_count(&cloned_reference));
 
+    println!("{}", rust_kernel::allocator::stats());
+
     #[cfg(test)]
     test_main();
     
@@ -1,65 +1,191 @@
 use x86_64::{
-    VirtAddr, 
+    VirtAddr,
+    instructions::interrupts,
     PhysAddr,
-    registers::control::Cr3, 
+    registers::control::{Cr0, Cr0Flags, Cr3},
     structures::paging::{
-        PhysFrame,
-        Size4KiB,
-        FrameAllocator,
+        Page,
         PageTable,
-        OffsetPageTable
+        PageTableFlags,
+        OffsetPageTable,
+        Translate,
+        mapper::{MappedFrame, TranslateResult},
     }
 };
+use core::arch::x86_64::__cpuid;
+use spin::{Mutex, Once};
 
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+pub mod bitmap;
+pub mod buddy;
+pub mod vmm;
+pub mod cow;
+pub mod page_tables;
+pub mod address_space;
+pub mod protection;
+
+use bitmap::BitmapFrameAllocator;
+use vmm::{Region, RegionKind, VmmError};
+pub use page_tables::{dump_page_tables, explain_translation};
+
+const PAGE_SIZE: u64 = 4096;
+
+// page mapper and frame allocator of the kernel address space
+// stored once paging is initialized, so memory can be mapped at runtime (e.g. to grow the heap)
+static KERNEL_MEMORY: Mutex<Option<(OffsetPageTable<'static>, BitmapFrameAllocator)>> = Mutex::new(None);
+
+// where the bootloader mapped the physical memory, set by `init`
+static PHYSICAL_MEMORY_OFFSET: Once<VirtAddr> = Once::new();
 
 
 pub unsafe fn init(physical_memory_offset: VirtAddr)-> OffsetPageTable<'static>{
+    PHYSICAL_MEMORY_OFFSET.call_once(|| physical_memory_offset);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
-unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
-    let (level_4_page_frame, _) = Cr3::read();
-    let physical_address = level_4_page_frame.start_address();
-    let virtual_address = physical_memory_offset + physical_address.as_u64();
-    let page_table_ptr: *mut PageTable = virtual_address.as_mut_ptr();
-    &mut *page_table_ptr
+// hands the mapper and frame allocator over to the memory subsystem
+pub fn init_kernel_memory(mut mapper: OffsetPageTable<'static>, mut frame_allocator: BitmapFrameAllocator) {
+    let mut kernel_memory = KERNEL_MEMORY.lock();
+    assert!(kernel_memory.is_none(), "kernel memory is already initialized");
+    // the kernel has to fault on writes to read only pages as well, copy on write depends on it
+    unsafe {Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT))};
+    protection::init(&mut mapper, frame_allocator.physical_memory_end().as_u64());
+    vmm::init(&mut mapper, &frame_allocator);
+    address_space::init(&mut mapper, &mut frame_allocator);
+    *kernel_memory = Some((mapper, frame_allocator));
+    drop(kernel_memory);
+
+    // the interrupt stacks can be moved to guarded stacks now
+    crate::gdt::init_interrupt_stacks();
+    protection::audit();
 }
 
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize,
+// runs `f` with the kernel mapper and frame allocator
+// `f` must not allocate on the heap, since growing the heap needs the same lock
+pub fn with_kernel_memory<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BitmapFrameAllocator) -> R) -> R {
+    interrupts::without_interrupts(|| {
+        let mut kernel_memory = KERNEL_MEMORY.lock();
+        let (mapper, frame_allocator) = kernel_memory
+                                        .as_mut()
+                                        .expect("kernel memory is not initialized");
+        f(mapper, frame_allocator)
+    })
 }
 
-impl BootInfoFrameAllocator {
-    pub unsafe fn init(memory_map:&'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
-        }
+// same as `with_kernel_memory`, but returns `None` instead of spinning when the lock is taken
+// or kernel memory is not initialized yet
+pub fn try_with_kernel_memory<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BitmapFrameAllocator) -> R) -> Option<R> {
+    interrupts::without_interrupts(|| {
+        let mut kernel_memory = KERNEL_MEMORY.try_lock()?;
+        let (mapper, frame_allocator) = kernel_memory.as_mut()?;
+        Some(f(mapper, frame_allocator))
+    })
+}
+
+// kernel stack in its own region, the page below the stack is left unmapped
+// so an overflow faults instead of overwriting whatever comes next in memory
+pub struct KernelStack {
+    region: Region,
+}
+
+impl KernelStack {
+    // the stack grows down from here
+    pub fn top(&self) -> VirtAddr {
+        self.region.end()
+    }
+
+    // lowest usable address
+    pub fn bottom(&self) -> VirtAddr {
+        self.region.start + PAGE_SIZE
     }
 
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        // get usable regions from memory map
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        
-        // map each region to its address range
-        let address_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
-        
-        // transform to an iterator of frame start addresses
-        let frame_addresses = address_ranges.flat_map(|r| r.step_by(4096));
-        
-        // create PhysFrame types from the start addresses 
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    pub fn guard_page(&self) -> Page {
+        Page::containing_address(self.region.start)
     }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        vmm::release(self.region.start).expect("kernel stack region is gone");
+    }
+}
+
+// maps a stack of `pages` pages with a guard page below it, the stack is freed when it is dropped
+pub fn alloc_kernel_stack(pages: usize) -> Result<KernelStack, VmmError> {
+    let region = vmm::allocate_guarded(
+        "kernel stack",
+        RegionKind::Stack,
+        pages as u64 * PAGE_SIZE,
+        PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+    )?;
+    Ok(KernelStack { region })
+}
+
+// size of the page that maps an address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingSize {
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
 }
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator{
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next +=1;
-        frame
+impl MappingSize {
+    pub fn bytes(self) -> u64 {
+        match self {
+            MappingSize::Size4KiB => 4096,
+            MappingSize::Size2MiB => 2 * 1024 * 1024,
+            MappingSize::Size1GiB => 1024 * 1024 * 1024,
+        }
     }
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Translation {
+    pub physical: PhysAddr,
+    pub page_size: MappingSize,
+    pub flags: PageTableFlags,
+}
+
+// walks the kernel page tables for `address`, `None` if it is not mapped
+pub fn translate(address: VirtAddr) -> Option<Translation> {
+    with_kernel_memory(|mapper, _| match mapper.translate(address) {
+        TranslateResult::Mapped { frame, offset, flags } => {
+            let page_size = match frame {
+                MappedFrame::Size4KiB(_) => MappingSize::Size4KiB,
+                MappedFrame::Size2MiB(_) => MappingSize::Size2MiB,
+                MappedFrame::Size1GiB(_) => MappingSize::Size1GiB,
+            };
+            Some(Translation { physical: frame.start_address() + offset, page_size, flags })
+        }
+        _ => None,
+    })
+}
+
+// edx of cpuid 0x8000_0001, the extended feature bits
+fn extended_features() -> u32 {
+    let max_extended = __cpuid(0x8000_0000).eax;
+    if max_extended >= 0x8000_0001 { __cpuid(0x8000_0001).edx } else { 0 }
+}
+
+// 1 GiB pages are optional, cpuid 0x8000_0001 reports them in edx bit 26
+pub fn gigantic_pages_supported() -> bool {
+    extended_features() & (1 << 26) != 0
+}
+
+pub fn physical_memory_offset() -> VirtAddr {
+    *PHYSICAL_MEMORY_OFFSET.r#try().expect("paging is not initialized")
+}
+
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
+    let (level_4_page_frame, _) = Cr3::read();
+    let physical_address = level_4_page_frame.start_address();
+    let virtual_address = physical_memory_offset + physical_address.as_u64();
+    let page_table_ptr: *mut PageTable = virtual_address.as_mut_ptr();
+    &mut *page_table_ptr
+}
@@ -0,0 +1,250 @@
+use x86_64::{
+    VirtAddr,
+    PhysAddr,
+    registers::control::Cr3,
+    structures::paging::{
+        Page,
+        PageTable,
+        PageTableFlags,
+        PhysFrame,
+        Mapper,
+        Size4KiB,
+        Size2MiB,
+        Size1GiB,
+        Translate,
+        FrameAllocator,
+        FrameDeallocator,
+        OffsetPageTable,
+        page::PageRangeInclusive,
+        page_table::PageTableEntry,
+    },
+};
+
+use super::{with_kernel_memory, physical_memory_offset, protection, bitmap::BitmapFrameAllocator, vmm::{self, RegionKind, VmmError}};
+
+const PAGE_SIZE: u64 = 4096;
+
+// the lower half of the address space above the dynamic window belongs to the user,
+// level 4 entries 192 to 255, every other entry is shared with the kernel
+pub const USER_START: u64 = 0x_6000_0000_0000;
+pub const USER_END: u64 = 0x_8000_0000_0000;
+
+const FIRST_USER_ENTRY: usize = (USER_START >> 39) as usize;
+const LAST_USER_ENTRY: usize = ((USER_END - 1) >> 39) as usize;
+
+// user page tables have to allow user access on every level, the pages decide about the rest
+const USER_TABLE_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE);
+
+fn is_user_entry(index: usize) -> bool {
+    (FIRST_USER_ENTRY..=LAST_USER_ENTRY).contains(&index)
+}
+
+fn table_at(frame: PhysFrame) -> &'static mut PageTable {
+    let virtual_address = physical_memory_offset() + frame.start_address().as_u64();
+    unsafe {&mut *virtual_address.as_mut_ptr()}
+}
+
+// the kernel entries are copied into every address space when it is created, so every kernel
+// entry that may be used later gets its level 3 table now
+// without it, kernel mappings made later would be missing from the existing address spaces
+pub(super) fn init(mapper: &mut OffsetPageTable<'static>, frame_allocator: &mut BitmapFrameAllocator) {
+    let level_4_table = mapper.level_4_table();
+    assert!(
+        level_4_table.iter().skip(FIRST_USER_ENTRY).take(LAST_USER_ENTRY - FIRST_USER_ENTRY + 1).all(|e| e.is_unused()),
+        "the user half is already mapped"
+    );
+
+    let first_dynamic = (vmm::DYNAMIC_START >> 39) as usize;
+    let last_dynamic = ((vmm::DYNAMIC_END - 1) >> 39) as usize;
+    for entry in level_4_table.iter_mut().skip(first_dynamic).take(last_dynamic - first_dynamic + 1) {
+        if entry.is_unused() {
+            let frame = frame_allocator.allocate_frame().expect("no frames left for kernel page tables");
+            table_at(frame).zero();
+            entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        }
+    }
+
+    vmm::reserve_at("user space", RegionKind::User, VirtAddr::new(USER_START), USER_END - USER_START)
+        .expect("the user half overlaps another region");
+}
+
+// the level 4 table of the kernel, set up by the bootloader
+fn kernel_level_4_frame() -> PhysFrame {
+    with_kernel_memory(|mapper, _| {
+        let table: *const PageTable = mapper.level_4_table();
+        let physical = VirtAddr::from_ptr(table) - physical_memory_offset();
+        PhysFrame::containing_address(PhysAddr::new(physical))
+    })
+}
+
+// the page tables the cpu walks right now, those of the active address space
+pub(super) fn active_mapper() -> OffsetPageTable<'static> {
+    unsafe {OffsetPageTable::new(table_at(Cr3::read().0), physical_memory_offset())}
+}
+
+// switches back to the kernel address space
+pub fn activate_kernel() {
+    let (_, flags) = Cr3::read();
+    unsafe {Cr3::write(kernel_level_4_frame(), flags)};
+}
+
+// an address space with its own level 4 table
+// the kernel half is shared with every other address space, the user half (`USER_START..USER_END`)
+// belongs to this address space alone
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    // an address space with the kernel mapped and an empty user half
+    pub fn new() -> Result<Self, VmmError> {
+        let kernel_frame = kernel_level_4_frame();
+        let level_4_frame = with_kernel_memory(|_, frame_allocator| frame_allocator.allocate_frame())
+            .ok_or(VmmError::FrameAllocationFailed)?;
+
+        let kernel_table = table_at(kernel_frame);
+        let table = table_at(level_4_frame);
+        table.zero();
+        for (index, entry) in kernel_table.iter().enumerate() {
+            if !is_user_entry(index) {
+                table[index] = entry.clone();
+            }
+        }
+        Ok(AddressSpace { level_4_frame })
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    // loads the level 4 table into cr3, the kernel stays mapped
+    pub fn activate(&self) {
+        let (_, flags) = Cr3::read();
+        unsafe {Cr3::write(self.level_4_frame, flags)};
+    }
+
+    fn mapper(&mut self) -> OffsetPageTable<'static> {
+        unsafe {OffsetPageTable::new(table_at(self.level_4_frame), physical_memory_offset())}
+    }
+
+    // maps fresh zeroed frames to `start..start + size` in the user half
+    // `USER_ACCESSIBLE` is added to the flags, on failure the pages mapped so far are unmapped again
+    pub fn map_user(&mut self, start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), VmmError> {
+        check_user_range(start, size)?;
+        let flags = protection::enforce(flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE);
+        let mut mapper = self.mapper();
+        let pages = user_pages(start, size);
+
+        with_kernel_memory(|_, frame_allocator| {
+            for page in pages {
+                let frame = frame_allocator.allocate_frame().ok_or(VmmError::FrameAllocationFailed);
+                let mapped = frame.and_then(|frame| {
+                    let frame_ptr: *mut u8 = (physical_memory_offset() + frame.start_address().as_u64()).as_mut_ptr();
+                    unsafe {
+                        frame_ptr.write_bytes(0, PAGE_SIZE as usize);
+                        match mapper.map_to_with_table_flags(page, frame, flags, USER_TABLE_FLAGS, frame_allocator) {
+                            // only matters if this address space is active
+                            Ok(flush) => flush.flush(),
+                            Err(error) => {
+                                frame_allocator.deallocate_frame(frame);
+                                return Err(error.into());
+                            }
+                        }
+                    }
+                    Ok(())
+                });
+                if let Err(error) = mapped {
+                    if page.start_address() > start {
+                        unmap_pages(&mut mapper, user_pages(start, page.start_address() - start), frame_allocator);
+                    }
+                    return Err(error);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    // unmaps `start..start + size` from the user half and frees the frames, unmapped pages are skipped
+    pub fn unmap_user(&mut self, start: VirtAddr, size: u64) -> Result<(), VmmError> {
+        check_user_range(start, size)?;
+        let mut mapper = self.mapper();
+        let pages = user_pages(start, size);
+        with_kernel_memory(|_, frame_allocator| unmap_pages(&mut mapper, pages, frame_allocator));
+        Ok(())
+    }
+
+    pub fn translate(&mut self, address: VirtAddr) -> Option<PhysAddr> {
+        self.mapper().translate_addr(address)
+    }
+}
+
+impl Drop for AddressSpace {
+    // frees the user pages, the page tables of the user half and the level 4 table
+    fn drop(&mut self) {
+        if self.is_active() {
+            activate_kernel();
+        }
+        let table = table_at(self.level_4_frame);
+        with_kernel_memory(|_, frame_allocator| unsafe {
+            for entry in table.iter_mut().skip(FIRST_USER_ENTRY).take(LAST_USER_ENTRY - FIRST_USER_ENTRY + 1) {
+                free_entry(entry, 4, frame_allocator);
+            }
+            frame_allocator.deallocate_frame(self.level_4_frame);
+        });
+    }
+}
+
+fn check_user_range(start: VirtAddr, size: u64) -> Result<(), VmmError> {
+    let in_user_half = start.as_u64() >= USER_START && start.as_u64().checked_add(size).is_some_and(|end| end <= USER_END);
+    if size == 0 || !start.is_aligned(PAGE_SIZE) || !size.is_multiple_of(PAGE_SIZE) || !in_user_half {
+        return Err(VmmError::InvalidRange);
+    }
+    Ok(())
+}
+
+// the end of the user half is not canonical, so the range is built from the last page
+fn user_pages(start: VirtAddr, size: u64) -> PageRangeInclusive<Size4KiB> {
+    Page::range_inclusive(Page::containing_address(start), Page::containing_address(start + (size - 1)))
+}
+
+fn unmap_pages(
+    mapper: &mut OffsetPageTable<'static>,
+    pages: PageRangeInclusive,
+    frame_allocator: &mut BitmapFrameAllocator,
+    ) {
+        for page in pages {
+            if let Ok((frame, flush)) = mapper.unmap(page) {
+                flush.flush();
+                unsafe {frame_allocator.deallocate_frame(frame)};
+            }
+        }
+}
+
+// frees what the entry points to, tables are freed with everything below them
+unsafe fn free_entry(entry: &mut PageTableEntry, level: u8, frame_allocator: &mut BitmapFrameAllocator) {
+    let flags = entry.flags();
+    if !flags.contains(PageTableFlags::PRESENT) {
+        return;
+    }
+    let frame = PhysFrame::containing_address(entry.addr());
+    match level {
+        1 => frame_allocator.deallocate_frame(frame),
+        2 if flags.contains(PageTableFlags::HUGE_PAGE) =>
+            frame_allocator.deallocate_large_frame(PhysFrame::<Size2MiB>::containing_address(entry.addr())),
+        3 if flags.contains(PageTableFlags::HUGE_PAGE) =>
+            frame_allocator.deallocate_large_frame(PhysFrame::<Size1GiB>::containing_address(entry.addr())),
+        _ => {
+            for child in table_at(frame).iter_mut() {
+                free_entry(child, level - 1, frame_allocator);
+            }
+            frame_allocator.deallocate_frame(frame);
+        }
+    }
+    entry.set_unused();
+}
@@ -0,0 +1,236 @@
+use x86_64::{
+    VirtAddr,
+    PhysAddr,
+    structures::paging::{
+        PageSize,
+        PhysFrame,
+        Size4KiB,
+        FrameAllocator,
+        FrameDeallocator,
+        frame::PhysFrameRange,
+    }
+};
+use core::slice;
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+
+const FRAME_SIZE: u64 = 4096;
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+// physical frame allocator that keeps one bit per frame, a set bit means the frame is in use
+// the bitmap itself is stored in the first usable region large enough to hold it
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    // additional references of every frame, a frame shared by two mappings has one
+    // stored right after the bitmap, a frame is only freed once it has no additional references
+    shares: &'static mut [u16],
+    // index of the first word that might contain a free frame
+    next_word: usize,
+    total_frames: usize,
+    free_frames: usize,
+    // end of the highest region in the memory map, usable or not
+    memory_end: PhysAddr,
+}
+
+impl BitmapFrameAllocator {
+    /// Builds the bitmap from the usable regions of the bootloader memory map.
+    ///
+    /// # Safety
+    /// The caller must guarantee that the memory map is valid, that all `Usable` frames are
+    /// really unused and that the complete physical memory is mapped at `physical_memory_offset`.
+    /// Must be called only once, since the frames are handed out again otherwise.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || memory_map
+                                .iter()
+                                .filter(|r| r.region_type == MemoryRegionType::Usable);
+
+        // only frames below the end of the last usable region need to be tracked
+        let max_frame = usable_regions()
+                        .map(|r| r.range.end_frame_number)
+                        .max()
+                        .unwrap_or(0) as usize;
+        let memory_end = memory_map.iter().map(|r| r.range.end_addr()).max().unwrap_or(0);
+        let words = max_frame.div_ceil(BITS_PER_WORD);
+        let frames = words * BITS_PER_WORD;
+        let bitmap_bytes = (words * 8 + frames * 2) as u64;
+        let bitmap_frames = bitmap_bytes.div_ceil(FRAME_SIZE);
+
+        // steal the frames for the bitmap from the start of the first region that fits
+        let bitmap_start = usable_regions()
+                        .find(|r| r.range.end_frame_number - r.range.start_frame_number >= bitmap_frames)
+                        .map(|r| r.range.start_addr())
+                        .expect("no usable region is large enough for the frame bitmap");
+
+        let bitmap_ptr: *mut u64 = (physical_memory_offset + bitmap_start).as_mut_ptr();
+        let bitmap = slice::from_raw_parts_mut(bitmap_ptr, words);
+        let shares = slice::from_raw_parts_mut(bitmap_ptr.add(words) as *mut u16, frames);
+
+        // everything is used until the memory map says otherwise
+        bitmap.fill(u64::MAX);
+        shares.fill(0);
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            shares,
+            next_word: 0,
+            total_frames: 0,
+            free_frames: 0,
+            memory_end: PhysAddr::new(memory_end),
+        };
+
+        for region in usable_regions() {
+            for frame in region.range.start_frame_number..region.range.end_frame_number {
+                allocator.clear_bit(frame as usize);
+                allocator.total_frames += 1;
+                allocator.free_frames += 1;
+            }
+        }
+
+        let first_bitmap_frame = (bitmap_start / FRAME_SIZE) as usize;
+        for frame in first_bitmap_frame..first_bitmap_frame + bitmap_frames as usize {
+            allocator.set_bit(frame);
+            allocator.free_frames -= 1;
+        }
+
+        allocator
+    }
+
+    // number of usable frames that are not allocated
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    // number of usable frames that are allocated, including the frames holding the bitmap
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+
+    // number of usable frames in the memory map
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    // the bootloader maps the physical memory up to this address
+    pub fn physical_memory_end(&self) -> PhysAddr {
+        self.memory_end
+    }
+
+    pub fn is_allocated(&self, frame: PhysFrame) -> bool {
+        let index = Self::frame_index(frame);
+        // frames outside of the bitmap are never handed out
+        index / BITS_PER_WORD >= self.bitmap.len() || self.is_set(index)
+    }
+
+    // number of mappings of an allocated frame, 0 for free frames
+    pub fn reference_count(&self, frame: PhysFrame) -> usize {
+        let index = Self::frame_index(frame);
+        if index / BITS_PER_WORD >= self.bitmap.len() || !self.is_set(index) {
+            return 0;
+        }
+        1 + self.shares[index] as usize
+    }
+
+    // adds a reference to an allocated frame, e.g. for a copy on write mapping
+    // `deallocate_frame` drops one reference, the frame is freed with the last one
+    pub fn add_reference(&mut self, frame: PhysFrame) {
+        let index = Self::frame_index(frame);
+        assert!(self.reference_count(frame) > 0, "adding a reference to free frame {frame:?}");
+        self.shares[index] = self.shares[index].checked_add(1).expect("too many references to a frame");
+    }
+
+    // allocates `count` physically contiguous frames, the first frame is aligned to `align` frames
+    // this scans the whole bitmap, so it is meant for carving out large pools at boot
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrameRange> {
+        assert!(align.is_power_of_two(), "alignment has to be a power of two");
+        let frames = self.bitmap.len() * BITS_PER_WORD;
+
+        let mut start = 0;
+        while start + count <= frames {
+            match (start..start + count).rev().find(|&index| self.is_set(index)) {
+                // no run fits below the used frame, retry after it
+                Some(used) => start = (used + 1).next_multiple_of(align),
+                None => {
+                    for index in start..start + count {
+                        self.set_bit(index);
+                    }
+                    self.free_frames -= count;
+
+                    let first = PhysFrame::containing_address(PhysAddr::new(start as u64 * FRAME_SIZE));
+                    return Some(PhysFrame::range(first, first + count as u64));
+                }
+            }
+        }
+        None
+    }
+
+    // a 2 MiB or 1 GiB frame is a run of 4 KiB frames aligned to its size
+    pub fn allocate_large_frame<S: PageSize>(&mut self) -> Option<PhysFrame<S>> {
+        let count = (S::SIZE / FRAME_SIZE) as usize;
+        let range = self.allocate_contiguous(count, count)?;
+        Some(PhysFrame::containing_address(range.start.start_address()))
+    }
+
+    /// Frees every 4 KiB frame of a frame returned by `allocate_large_frame`.
+    ///
+    /// # Safety
+    ///
+    /// The frame must be unused.
+    pub unsafe fn deallocate_large_frame<S: PageSize>(&mut self, frame: PhysFrame<S>) {
+        let first = PhysFrame::<Size4KiB>::containing_address(frame.start_address());
+        for small_frame in PhysFrame::range(first, first + S::SIZE / FRAME_SIZE) {
+            FrameDeallocator::<Size4KiB>::deallocate_frame(self, small_frame);
+        }
+    }
+
+    fn frame_index(frame: PhysFrame) -> usize {
+        (frame.start_address().as_u64() / FRAME_SIZE) as usize
+    }
+
+    fn is_set(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    fn set_bit(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+    }
+
+    fn clear_bit(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        // words before `next_word` are known to be full, so the scan starts there
+        // every full word is skipped at most once between deallocations
+        let offset = self.bitmap[self.next_word..]
+                        .iter()
+                        .position(|&word| word != u64::MAX)?;
+        self.next_word += offset;
+
+        let bit = self.bitmap[self.next_word].trailing_ones() as usize;
+        let index = self.next_word * BITS_PER_WORD + bit;
+        self.set_bit(index);
+        self.free_frames -= 1;
+
+        let address = PhysAddr::new(index as u64 * FRAME_SIZE);
+        Some(PhysFrame::containing_address(address))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let index = Self::frame_index(frame);
+        assert!(index / BITS_PER_WORD < self.bitmap.len(), "deallocating unmanaged frame {frame:?}");
+        assert!(self.is_allocated(frame), "double free of frame {frame:?}");
+
+        // a shared frame stays allocated for the other mappings
+        if self.shares[index] > 0 {
+            self.shares[index] -= 1;
+            return;
+        }
+        self.clear_bit(index);
+        self.free_frames += 1;
+        self.next_word = self.next_word.min(index / BITS_PER_WORD);
+    }
+}
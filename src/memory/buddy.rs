@@ -0,0 +1,283 @@
+use x86_64::{
+    PhysAddr,
+    instructions::interrupts,
+    structures::paging::{
+        PhysFrame,
+        Size4KiB,
+        FrameAllocator,
+        FrameDeallocator,
+        frame::PhysFrameRange,
+    }
+};
+use spin::Mutex;
+
+use super::{physical_memory_offset, with_kernel_memory, vmm::VmmError};
+
+const FRAME_SIZE: u64 = 4096;
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+// the largest block handed out is 2^MAX_ORDER frames (4 MiB)
+pub const MAX_ORDER: usize = 10;
+
+// ranges that can be handed to one allocator and the frames they may hold together (128 MiB)
+pub const MAX_REGIONS: usize = 8;
+pub const MAX_FRAMES: usize = 1 << 15;
+
+// frames the global allocator takes from the kernel frame allocator, a single block of the largest order
+pub const POOL_FRAMES: usize = 1 << MAX_ORDER;
+
+// header at the start of every free block, links the free blocks of the same order
+// the blocks are accessed through the physical memory mapping
+#[derive(Clone, Copy)]
+struct FreeBlock {
+    order: usize,
+    prev: Option<u64>,
+    next: Option<u64>,
+}
+
+// physical range given to the allocator, its frames start at bit `first_bit` of the free bitmap
+#[derive(Debug, Clone, Copy)]
+struct Region {
+    start: u64,
+    end: u64,
+    first_bit: usize,
+}
+
+// buddy system allocator for physically contiguous blocks of 2^order frames
+// a block of order `n` is always aligned to its own size, so the buddy of a block
+// is found by flipping the bit of its address that corresponds to the block size
+// the free lists are linked through the free blocks, a bitmap marks the first frame of every
+// free block, so no memory is needed besides the allocator itself
+pub struct BuddyFrameAllocator {
+    // start address of the first free block of each order
+    free_lists: [Option<u64>; MAX_ORDER + 1],
+    free_blocks: [usize; MAX_ORDER + 1],
+    // a set bit means a free block starts at the frame
+    free_heads: [u64; MAX_FRAMES / BITS_PER_WORD],
+    regions: [Option<Region>; MAX_REGIONS],
+    total_frames: usize,
+    free_frames: usize,
+}
+
+impl BuddyFrameAllocator {
+    pub const fn new() -> Self {
+        BuddyFrameAllocator {
+            free_lists: [None; MAX_ORDER + 1],
+            free_blocks: [0; MAX_ORDER + 1],
+            free_heads: [0; MAX_FRAMES / BITS_PER_WORD],
+            regions: [None; MAX_REGIONS],
+            total_frames: 0,
+            free_frames: 0,
+        }
+    }
+
+    /// Hands a range of physical frames to the allocator.
+    ///
+    /// Panics if the allocator already has `MAX_REGIONS` regions or would hold more than `MAX_FRAMES` frames.
+    ///
+    /// # Safety
+    /// The caller must guarantee that the frames are unused, not managed by any other allocator
+    /// and mapped at the physical memory offset.
+    pub unsafe fn add_region(&mut self, range: PhysFrameRange) {
+        let mut start = range.start.start_address().as_u64();
+        let end = range.end.start_address().as_u64();
+        let frames = |start: u64, end: u64| ((end - start) / FRAME_SIZE) as usize;
+        let first_bit = self.regions.iter().flatten().map(|r| r.first_bit + frames(r.start, r.end)).max().unwrap_or(0);
+        assert!(first_bit + frames(start, end) <= MAX_FRAMES, "the buddy allocator holds at most {MAX_FRAMES} frames");
+        let slot = self.regions.iter_mut().find(|slot| slot.is_none()).expect("the buddy allocator has no region left");
+        *slot = Some(Region { start, end, first_bit });
+
+        // split the range into the largest naturally aligned blocks that fit
+        while start < end {
+            let mut order = MAX_ORDER;
+            while start % Self::block_size(order) != 0 || start + Self::block_size(order) > end {
+                order -= 1;
+            }
+            self.total_frames += 1 << order;
+            self.free_frames += 1 << order;
+            self.insert_free_block(start, order);
+            start += Self::block_size(order);
+        }
+    }
+
+    // allocates 2^order physically contiguous frames, aligned to their size
+    pub fn allocate(&mut self, order: usize) -> Option<PhysFrame> {
+        assert!(order <= MAX_ORDER, "order {order} is larger than MAX_ORDER");
+
+        // take a block from the smallest order that has one
+        let mut current = (order..=MAX_ORDER).find(|&o| self.free_lists[o].is_some())?;
+        let address = self.free_lists[current]?;
+        self.remove_free(address, current);
+
+        // split it in halves until it has the requested size, the upper halves stay free
+        while current > order {
+            current -= 1;
+            self.push_free(address + Self::block_size(current), current);
+        }
+
+        self.free_frames -= 1 << order;
+        Some(PhysFrame::containing_address(PhysAddr::new(address)))
+    }
+
+    /// Returns a block of 2^order frames to the allocator and merges it with its free buddies.
+    ///
+    /// Panics on a double free, i.e. if the block is part of a free block.
+    ///
+    /// # Safety
+    /// The caller must guarantee that the block was allocated with the same order and is no longer in use.
+    pub unsafe fn deallocate(&mut self, frame: PhysFrame, order: usize) {
+        let address = frame.start_address().as_u64();
+        assert!(self.is_managed(address, order), "block {frame:?} (order {order}) is not managed by the allocator");
+        if let Some(free) = (order..=MAX_ORDER).find(|&o| self.free_block_covers(address, o)) {
+            panic!("double free of block {frame:?} (order {order}), it is part of a free block of order {free}");
+        }
+        self.free_frames += 1 << order;
+        self.insert_free_block(address, order);
+    }
+
+    fn insert_free_block(&mut self, mut address: u64, mut order: usize) {
+        // as long as the buddy is free, both merge into a block of the next order
+        while order < MAX_ORDER {
+            let buddy = address ^ Self::block_size(order);
+            if !self.is_free(buddy, order) {
+                break;
+            }
+            self.remove_free(buddy, order);
+            address = address.min(buddy);
+            order += 1;
+        }
+        self.push_free(address, order);
+    }
+
+    // the free block of order `order` that would contain `address` really is free
+    fn free_block_covers(&self, address: u64, order: usize) -> bool {
+        let start = address & !(Self::block_size(order) - 1);
+        self.is_managed(start, 0) && self.head_bit(start) && Self::header(start).order >= order
+    }
+
+    fn is_free(&self, address: u64, order: usize) -> bool {
+        // the header of an allocated block holds data, it only counts with the bit set
+        self.is_managed(address, order) && self.head_bit(address) && Self::header(address).order == order
+    }
+
+    fn push_free(&mut self, address: u64, order: usize) {
+        let next = self.free_lists[order];
+        Self::write_header(address, FreeBlock { order, prev: None, next });
+        if let Some(next) = next {
+            Self::write_header(next, FreeBlock { prev: Some(address), ..Self::header(next) });
+        }
+        self.free_lists[order] = Some(address);
+        self.free_blocks[order] += 1;
+        self.set_head_bit(address, true);
+    }
+
+    fn remove_free(&mut self, address: u64, order: usize) {
+        let block = Self::header(address);
+        match block.prev {
+            Some(prev) => Self::write_header(prev, FreeBlock { next: block.next, ..Self::header(prev) }),
+            None => self.free_lists[order] = block.next,
+        }
+        if let Some(next) = block.next {
+            Self::write_header(next, FreeBlock { prev: block.prev, ..Self::header(next) });
+        }
+        self.free_blocks[order] -= 1;
+        self.set_head_bit(address, false);
+    }
+
+    fn header(address: u64) -> FreeBlock {
+        let virtual_address = physical_memory_offset() + address;
+        unsafe {virtual_address.as_ptr::<FreeBlock>().read()}
+    }
+
+    fn write_header(address: u64, block: FreeBlock) {
+        let virtual_address = physical_memory_offset() + address;
+        unsafe {virtual_address.as_mut_ptr::<FreeBlock>().write(block)};
+    }
+
+    // the block lies in one of the regions, so its header may be read
+    fn is_managed(&self, address: u64, order: usize) -> bool {
+        let end = address + Self::block_size(order);
+        self.regions.iter().flatten().any(|r| r.start <= address && end <= r.end)
+    }
+
+    // bit of a frame in a region
+    fn bit(&self, address: u64) -> usize {
+        let region = self.regions.iter().flatten()
+            .find(|r| r.start <= address && address < r.end)
+            .expect("frame is not managed by the buddy allocator");
+        region.first_bit + ((address - region.start) / FRAME_SIZE) as usize
+    }
+
+    fn head_bit(&self, address: u64) -> bool {
+        let bit = self.bit(address);
+        self.free_heads[bit / BITS_PER_WORD] & (1 << (bit % BITS_PER_WORD)) != 0
+    }
+
+    fn set_head_bit(&mut self, address: u64, free: bool) {
+        let bit = self.bit(address);
+        if free {
+            self.free_heads[bit / BITS_PER_WORD] |= 1 << (bit % BITS_PER_WORD);
+        } else {
+            self.free_heads[bit / BITS_PER_WORD] &= !(1 << (bit % BITS_PER_WORD));
+        }
+    }
+
+    fn block_size(order: usize) -> u64 {
+        FRAME_SIZE << order
+    }
+
+    // number of frames that are not allocated
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    // number of frames given to the allocator with `add_region`
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    // number of free blocks of exactly the given order
+    pub fn free_blocks(&self, order: usize) -> usize {
+        self.free_blocks[order]
+    }
+
+    // order of the largest block that can currently be allocated
+    pub fn largest_free_order(&self) -> Option<usize> {
+        (0..=MAX_ORDER).rev().find(|&order| self.free_lists[order].is_some())
+    }
+}
+
+impl Default for BuddyFrameAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.allocate(0)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.deallocate(frame, 0);
+    }
+}
+
+// the global allocator for physically contiguous blocks, e.g. for DMA buffers
+static BUDDY: Mutex<BuddyFrameAllocator> = Mutex::new(BuddyFrameAllocator::new());
+
+// takes `frames` contiguous frames from the kernel frame allocator for the global buddy allocator
+// aligned to the largest block, so they split into as few blocks as possible
+pub fn init(frames: usize) -> Result<(), VmmError> {
+    let pool = with_kernel_memory(|_, frame_allocator| frame_allocator.allocate_contiguous(frames, 1 << MAX_ORDER))
+        .ok_or(VmmError::FrameAllocationFailed)?;
+    interrupts::without_interrupts(|| unsafe {BUDDY.lock().add_region(pool)});
+    Ok(())
+}
+
+// runs `f` with the global buddy allocator
+pub fn with_buddy<R>(f: impl FnOnce(&mut BuddyFrameAllocator) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut BUDDY.lock()))
+}
@@ -0,0 +1,116 @@
+use x86_64::structures::paging::{
+    Page,
+    PageTableFlags,
+    PhysFrame,
+    Mapper,
+    Size4KiB,
+    Translate,
+    FrameAllocator,
+    FrameDeallocator,
+    OffsetPageTable,
+    mapper::{MappedFrame, TranslateResult},
+};
+
+use super::bitmap::BitmapFrameAllocator;
+use super::vmm::VmmError;
+
+const PAGE_SIZE: usize = 4096;
+
+// software bit of a page table entry, set on pages that were writable before they were shared
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+// flags of new page tables, the entries of the table decide about the access rights
+pub(super) const TABLE_FLAGS: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE);
+
+fn mapped_frame(mapper: &OffsetPageTable<'static>, page: Page) -> Option<(PhysFrame, PageTableFlags)> {
+    match mapper.translate(page.start_address()) {
+        TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. } => Some((frame, flags)),
+        _ => None,
+    }
+}
+
+// maps `target` to the frame of `source`, both pages become read only until the next write
+// `source` has to be a 4 KiB page
+// the frame gets another reference, so it is only freed once both pages are unmapped
+pub fn share_page(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BitmapFrameAllocator,
+    source: Page,
+    target: Page,
+    ) -> Result<(), VmmError> {
+        let (frame, flags) = match mapper.translate(source.start_address()) {
+            TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. } => (frame, flags),
+            // reference counts are kept per 4 KiB frame
+            TranslateResult::Mapped { .. } => return Err(VmmError::HugePage),
+            _ => return Err(VmmError::NotMapped),
+        };
+        let shared_flags = if flags.contains(PageTableFlags::WRITABLE) {
+            (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE
+        } else {
+            flags
+        };
+
+        unsafe {
+            mapper.update_flags(source, shared_flags).map_err(|_| VmmError::NotMapped)?.flush();
+            frame_allocator.add_reference(frame);
+            match mapper.map_to_with_table_flags(target, frame, shared_flags, TABLE_FLAGS, frame_allocator) {
+                Ok(flush) => flush.flush(),
+                Err(error) => {
+                    frame_allocator.deallocate_frame(frame);
+                    return Err(error.into());
+                }
+            }
+        }
+        Ok(())
+}
+
+// called on a write fault, gives `page` its own writable frame if it is a copy on write page
+// returns false if the page is not copy on write or no frame is left
+pub fn handle_write_fault(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BitmapFrameAllocator,
+    page: Page<Size4KiB>,
+    ) -> bool {
+        let (frame, flags) = match mapped_frame(mapper, page) {
+            Some((frame, flags)) if flags.contains(COPY_ON_WRITE) => (frame, flags),
+            _ => return false,
+        };
+        let writable = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+
+        // the other mappings are gone, the frame can be written in place
+        if frame_allocator.reference_count(frame) == 1 {
+            return match unsafe {mapper.update_flags(page, writable)} {
+                Ok(flush) => {
+                    flush.flush();
+                    true
+                }
+                Err(_) => false,
+            };
+        }
+
+        let copy = match frame_allocator.allocate_frame() {
+            Some(copy) => copy,
+            None => return false,
+        };
+        let physical_memory = mapper.phys_offset();
+        unsafe {
+            let from: *const u8 = (physical_memory + frame.start_address().as_u64()).as_ptr();
+            let to: *mut u8 = (physical_memory + copy.start_address().as_u64()).as_mut_ptr();
+            core::ptr::copy_nonoverlapping(from, to, PAGE_SIZE);
+
+            // interrupts are disabled by `try_with_kernel_memory`, nothing runs between unmap and map
+            match mapper.unmap(page) {
+                Ok((_, flush)) => flush.flush(),
+                Err(_) => {
+                    frame_allocator.deallocate_frame(copy);
+                    return false;
+                }
+            }
+            mapper.map_to_with_table_flags(page, copy, writable, TABLE_FLAGS, frame_allocator)
+                .expect("remapping a copied page failed")
+                .flush();
+            // drops the reference of this page, the other mappings keep the frame
+            frame_allocator.deallocate_frame(frame);
+        }
+        true
+}
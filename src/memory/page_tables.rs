@@ -0,0 +1,218 @@
+use core::fmt;
+use x86_64::{
+    VirtAddr,
+    PhysAddr,
+    registers::control::Cr3,
+    structures::paging::{PageTable, PageTableFlags, PageTableIndex},
+};
+
+use super::{active_level_4_table, physical_memory_offset};
+use crate::serial_println;
+
+// set by the cpu on access, they would split runs that are otherwise the same
+const VOLATILE_FLAGS: PageTableFlags = PageTableFlags::ACCESSED.union(PageTableFlags::DIRTY);
+
+// virtual range mapped to contiguous physical memory with the same flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappedRange {
+    pub start: VirtAddr,
+    pub size: u64,
+    pub physical: PhysAddr,
+    // flags of the last level combined with the levels above, like the cpu sees them:
+    // writable and user only if every level allows it, no execute if any level forbids it
+    pub flags: PageTableFlags,
+}
+
+impl MappedRange {
+    // last address of the range, the end itself doesn't fit into the top of the address space
+    pub fn last(&self) -> VirtAddr {
+        self.start + (self.size - 1)
+    }
+
+    pub fn contains(&self, address: VirtAddr) -> bool {
+        address >= self.start && address <= self.last()
+    }
+
+    // extends the range by a page that directly follows it in both address spaces
+    fn try_extend(&mut self, next: &MappedRange) -> bool {
+        let follows = self.last().as_u64().wrapping_add(1) == next.start.as_u64()
+            && self.physical + self.size == next.physical
+            && self.flags == next.flags;
+        if follows {
+            self.size += next.size;
+        }
+        follows
+    }
+}
+
+impl fmt::Display for MappedRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:#018x}-{:#018x} -> {:#012x} {:>10} KiB {}",
+            self.start.as_u64(), self.last().as_u64(), self.physical.as_u64(), self.size / 1024, Flags(self.flags)
+        )
+    }
+}
+
+// short form of the flags the dumps care about, e.g. `P W - NX -`
+pub struct Flags(pub PageTableFlags);
+
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |flag: PageTableFlags, name: &'static str, empty: &'static str| {
+            if self.0.contains(flag) { name } else { empty }
+        };
+        write!(
+            f,
+            "{} {} {} {} {}",
+            flag(PageTableFlags::PRESENT, "P", "-"),
+            flag(PageTableFlags::WRITABLE, "W", "-"),
+            flag(PageTableFlags::USER_ACCESSIBLE, "U", "-"),
+            flag(PageTableFlags::NO_EXECUTE, "NX", "--"),
+            flag(PageTableFlags::HUGE_PAGE, "H", "-"),
+        )
+    }
+}
+
+// a page table, through the physical memory mapping
+fn table_at(physical: PhysAddr) -> &'static PageTable {
+    let virtual_address = physical_memory_offset() + physical.as_u64();
+    unsafe {&*virtual_address.as_ptr()}
+}
+
+// flags of a table entry combined with the flags of the levels above
+fn combine(parent: PageTableFlags, entry: PageTableFlags) -> PageTableFlags {
+    let inherited = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    (entry - inherited) | (entry & parent & inherited) | (parent & PageTableFlags::NO_EXECUTE)
+}
+
+fn page_size(level: u8) -> u64 {
+    4096 << (9 * (level as u64 - 1))
+}
+
+fn walk_table(
+    table: &PageTable,
+    level: u8,
+    base: u64,
+    parent_flags: PageTableFlags,
+    f: &mut impl FnMut(MappedRange),
+    ) {
+        for (index, entry) in table.iter().enumerate() {
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) {
+                continue;
+            }
+            let start = base | (index as u64) << (12 + 9 * (level as u64 - 1));
+            let flags = combine(parent_flags, flags);
+            // level 1 entries always map pages, level 2 and 3 entries do when they are huge
+            if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+                f(MappedRange {
+                    start: VirtAddr::new_truncate(start),
+                    size: page_size(level),
+                    physical: entry.addr(),
+                    flags: flags - VOLATILE_FLAGS,
+                });
+            } else {
+                walk_table(table_at(entry.addr()), level - 1, start, flags, f);
+            }
+        }
+}
+
+// calls `f` for every mapped range of the active address space, in ascending virtual order
+// contiguous pages with the same flags are merged into one range
+pub fn for_each_mapping(mut f: impl FnMut(MappedRange)) {
+    let level_4_table = unsafe {active_level_4_table(physical_memory_offset())};
+    let all = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+
+    let mut current: Option<MappedRange> = None;
+    walk_table(level_4_table, 4, 0, all, &mut |range| {
+        if let Some(run) = current.as_mut() {
+            if run.try_extend(&range) {
+                return;
+            }
+            f(*run);
+        }
+        current = Some(range);
+    });
+    if let Some(run) = current {
+        f(run);
+    }
+}
+
+// prints every mapped range of the active address space over serial
+pub fn dump_page_tables() {
+    serial_println!("page tables (cr3 {:#x}):", Cr3::read().0.start_address().as_u64());
+    let mut ranges = 0;
+    let mut mapped = 0;
+    for_each_mapping(|range| {
+        serial_println!("  {}", range);
+        ranges += 1;
+        mapped += range.size;
+    });
+    serial_println!("{} ranges, {} KiB mapped", ranges, mapped / 1024);
+}
+
+// one level of the walk for an address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WalkStep {
+    // 4 for the level 4 table down to 1
+    pub level: u8,
+    pub table: PhysAddr,
+    pub index: PageTableIndex,
+    pub entry: PhysAddr,
+    pub flags: PageTableFlags,
+}
+
+// the page table walk for one address, from the level 4 table down to the entry that maps it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Walk {
+    pub address: VirtAddr,
+    pub steps: [Option<WalkStep>; 4],
+    // `None` if the walk ended at a not present entry
+    pub physical: Option<PhysAddr>,
+    pub page_size: u64,
+}
+
+pub fn walk(address: VirtAddr) -> Walk {
+    let indices = [address.p4_index(), address.p3_index(), address.p2_index(), address.p1_index()];
+    let mut walk = Walk { address, steps: [None; 4], physical: None, page_size: 0 };
+    let mut table = Cr3::read().0.start_address();
+
+    for (step, (&index, level)) in indices.iter().zip((1..=4u8).rev()).enumerate() {
+        let entry = &table_at(table)[index];
+        let flags = entry.flags();
+        walk.steps[step] = Some(WalkStep { level, table, index, entry: entry.addr(), flags });
+        if !flags.contains(PageTableFlags::PRESENT) {
+            break;
+        }
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            let size = page_size(level);
+            walk.physical = Some(entry.addr() + (address.as_u64() & (size - 1)));
+            walk.page_size = size;
+            break;
+        }
+        table = entry.addr();
+    }
+    walk
+}
+
+// prints every level of the page table walk for `address` over serial
+pub fn explain_translation(address: VirtAddr) {
+    let walk = walk(address);
+    serial_println!("translating {:#x}:", address.as_u64());
+    for step in walk.steps.iter().flatten() {
+        serial_println!(
+            "  level {} table {:#x} index {:>3} -> {:#x} {}",
+            step.level, step.table.as_u64(), u16::from(step.index), step.entry.as_u64(), Flags(step.flags)
+        );
+    }
+    match walk.physical {
+        Some(physical) => {
+            serial_println!("  -> {:#x} in a {} KiB page", physical.as_u64(), walk.page_size / 1024);
+        }
+        None => {
+            serial_println!("  -> not mapped");
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use x86_64::{
+    VirtAddr,
+    registers::model_specific::{Efer, EferFlags},
+    structures::paging::{
+        Page,
+        PageSize,
+        PageTableFlags,
+        Mapper,
+        Size4KiB,
+        Size2MiB,
+        Size1GiB,
+        Translate,
+        OffsetPageTable,
+        mapper::{MappedFrame, TranslateResult},
+    },
+};
+
+use super::{extended_features, page_tables::{self, MappedRange}};
+use crate::serial_println;
+
+// no execute is optional, cpuid 0x8000_0001 reports it in edx bit 20
+pub fn no_execute_supported() -> bool {
+    extended_features() & (1 << 20) != 0
+}
+
+pub fn no_execute_enabled() -> bool {
+    Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE)
+}
+
+// W^X: writable pages are never executable
+// without NXE the bit is reserved and would fault, so it is dropped instead
+pub fn enforce(flags: PageTableFlags) -> PageTableFlags {
+    if !no_execute_enabled() {
+        flags - PageTableFlags::NO_EXECUTE
+    } else if flags.contains(PageTableFlags::WRITABLE) {
+        flags | PageTableFlags::NO_EXECUTE
+    } else {
+        flags
+    }
+}
+
+// enables NXE and takes execute rights from the physical memory mapping of the bootloader,
+// it is only ever used for data
+pub(super) fn init(mapper: &mut OffsetPageTable<'static>, physical_memory_end: u64) {
+    if !no_execute_supported() {
+        serial_println!("W^X: the cpu doesn't support no execute pages, W^X is not enforced");
+        return;
+    }
+    unsafe {Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE))};
+
+    let start = mapper.phys_offset();
+    let end = start + physical_memory_end;
+    let mut address = start;
+    while address < end {
+        address += match mapper.translate(address) {
+            TranslateResult::Mapped { frame: MappedFrame::Size4KiB(_), flags, .. } =>
+                set_no_execute::<Size4KiB>(mapper, address, flags),
+            TranslateResult::Mapped { frame: MappedFrame::Size2MiB(_), flags, .. } =>
+                set_no_execute::<Size2MiB>(mapper, address, flags),
+            TranslateResult::Mapped { frame: MappedFrame::Size1GiB(_), flags, .. } =>
+                set_no_execute::<Size1GiB>(mapper, address, flags),
+            _ => Size4KiB::SIZE,
+        };
+    }
+}
+
+// returns the size of the page
+fn set_no_execute<S: PageSize>(mapper: &mut OffsetPageTable<'static>, address: VirtAddr, flags: PageTableFlags) -> u64
+where
+    OffsetPageTable<'static>: Mapper<S>,
+{
+    if let Ok(flush) = unsafe {mapper.update_flags(Page::<S>::containing_address(address), flags | PageTableFlags::NO_EXECUTE)} {
+        flush.flush();
+    }
+    S::SIZE
+}
+
+pub fn is_writable_and_executable(range: &MappedRange) -> bool {
+    range.flags.contains(PageTableFlags::WRITABLE) && !range.flags.contains(PageTableFlags::NO_EXECUTE)
+}
+
+// walks the active page tables and prints every range that is writable and executable,
+// returns the number of ranges
+// with the `wx-strict` feature any such range is fatal
+pub fn audit() -> usize {
+    let mut violations = 0;
+    let mut bytes = 0;
+    page_tables::for_each_mapping(|range| {
+        if is_writable_and_executable(&range) {
+            serial_println!("W^X: writable and executable {}", range);
+            violations += 1;
+            bytes += range.size;
+        }
+    });
+    if violations > 0 {
+        serial_println!("W^X: {} ranges, {} KiB writable and executable", violations, bytes / 1024);
+    }
+    if cfg!(feature = "wx-strict") {
+        assert!(violations == 0, "W^X: {} ranges are writable and executable", violations);
+    }
+    violations
+}
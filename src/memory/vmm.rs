@@ -0,0 +1,630 @@
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::{
+    VirtAddr,
+    PhysAddr,
+    structures::paging::{
+        Page,
+        PageSize,
+        PageTableFlags,
+        PhysFrame,
+        Mapper,
+        Size4KiB,
+        Size2MiB,
+        Size1GiB,
+        Translate,
+        FrameAllocator,
+        FrameDeallocator,
+        OffsetPageTable,
+        mapper::{MapToError, MappedFrame, TranslateResult},
+        page::PageRange,
+    },
+    structures::idt::PageFaultErrorCode,
+};
+use spin::Mutex;
+
+use super::{with_kernel_memory, try_with_kernel_memory, gigantic_pages_supported, bitmap::BitmapFrameAllocator, address_space, cow, protection};
+use crate::serial_println;
+
+const PAGE_SIZE: u64 = 4096;
+const MAX_REGIONS: usize = 64;
+
+// regions without a fixed address are placed in this window of the kernel address space
+// it covers level 4 entries 160 to 191, `init` checks that the bootloader left them unused
+pub const DYNAMIC_START: u64 = 0x_5000_0000_0000;
+pub const DYNAMIC_END: u64 = 0x_6000_0000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    // the complete physical memory, mapped by the bootloader at the physical memory offset
+    PhysicalMemory,
+    Heap,
+    Stack,
+    Mmio,
+    // memory owned by a single task
+    Task,
+    // the user half of every address space, mapped per address space instead of by the vmm
+    User,
+}
+
+// what backs the pages of a region
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    // only the address range is reserved, the owner of the region maps it
+    Reserved,
+    // fresh frames mapped by the vmm, they are freed with the region
+    Allocated,
+    // same as `Allocated`, but the lowest page stays unmapped so running off the bottom faults
+    Guarded,
+    // fixed physical frames starting at the address, e.g. device registers
+    Physical(PhysAddr),
+    // fresh zeroed frames mapped with the flags by the page fault handler on first touch
+    Lazy(PageTableFlags),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub name: &'static str,
+    pub kind: RegionKind,
+    pub backing: Backing,
+    pub start: VirtAddr,
+    // in bytes, always a multiple of the page size
+    pub size: u64,
+}
+
+impl Region {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, address: VirtAddr) -> bool {
+        self.start <= address && address < self.end()
+    }
+
+    pub fn pages(&self) -> PageRange<Size4KiB> {
+        let start = Page::containing_address(self.start);
+        Page::range(start, start + self.size / PAGE_SIZE)
+    }
+
+    fn overlaps(&self, start: VirtAddr, size: u64) -> bool {
+        start < self.end() && self.start < start + size
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:#014x}-{:#014x} {:>10} KiB {:<15} {:?}",
+            self.start.as_u64(), self.end().as_u64(), self.size / 1024, self.name, self.kind
+        )?;
+        match self.backing {
+            Backing::Reserved => write!(f, " (reserved)"),
+            Backing::Allocated => write!(f, " (allocated)"),
+            Backing::Guarded => write!(f, " (allocated, guard page)"),
+            Backing::Physical(address) => write!(f, " (physical {:#x})", address.as_u64()),
+            Backing::Lazy(_) => write!(f, " (lazy)"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmmError {
+    // the range overlaps an existing region
+    Overlap,
+    OutOfVirtualSpace,
+    TooManyRegions,
+    // the address or size is not page aligned, or the size is zero
+    InvalidRange,
+    // no region starts at the address
+    NotFound,
+    // a page that has to be mapped is not
+    NotMapped,
+    // the page is part of a 2 MiB or 1 GiB page, e.g. it can't be shared on its own
+    HugePage,
+    FrameAllocationFailed,
+    AlreadyMapped,
+    // a page table on the way to the page is a huge page
+    ParentEntryHugePage,
+}
+
+impl<S: PageSize> From<MapToError<S>> for VmmError {
+    fn from(error: MapToError<S>) -> Self {
+        match error {
+            MapToError::FrameAllocationFailed => VmmError::FrameAllocationFailed,
+            MapToError::ParentEntryHugePage => VmmError::ParentEntryHugePage,
+            MapToError::PageAlreadyMapped(_) => VmmError::AlreadyMapped,
+        }
+    }
+}
+
+// the regions sorted by their start address
+// a fixed array instead of a `Vec`, the heap itself is one of the regions
+#[derive(Clone, Copy)]
+pub struct RegionList {
+    regions: [Option<Region>; MAX_REGIONS],
+    len: usize,
+}
+
+impl RegionList {
+    const fn new() -> Self {
+        RegionList {
+            regions: [None; MAX_REGIONS],
+            len: 0,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Region> + '_ {
+        self.regions[..self.len].iter().flatten().copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn insert(&mut self, region: Region) -> Result<(), VmmError> {
+        if self.iter().any(|r| r.overlaps(region.start, region.size)) {
+            return Err(VmmError::Overlap);
+        }
+        if self.len == MAX_REGIONS {
+            return Err(VmmError::TooManyRegions);
+        }
+        let index = self.iter().take_while(|r| r.start < region.start).count();
+        self.regions.copy_within(index..self.len, index + 1);
+        self.regions[index] = Some(region);
+        self.len += 1;
+        Ok(())
+    }
+
+    fn remove(&mut self, start: VirtAddr) -> Option<Region> {
+        let index = self.iter().position(|r| r.start == start)?;
+        let region = self.regions[index].take();
+        self.regions.copy_within(index + 1..self.len, index);
+        self.len -= 1;
+        self.regions[self.len] = None;
+        region
+    }
+
+    // first gap of the dynamic window that fits `size` bytes starting at a multiple of `align`
+    fn find_free(&self, size: u64, align: u64) -> Option<VirtAddr> {
+        let mut candidate = align_up(DYNAMIC_START, align);
+        for region in self.iter() {
+            let (start, end) = (region.start.as_u64(), region.end().as_u64());
+            if end <= candidate {
+                continue;
+            }
+            if start >= candidate + size {
+                break;
+            }
+            candidate = align_up(end, align);
+        }
+        (candidate + size <= DYNAMIC_END).then(|| VirtAddr::new(candidate))
+    }
+}
+
+static REGIONS: Mutex<RegionList> = Mutex::new(RegionList::new());
+
+// page faults resolved by mapping a frame into a lazy region
+static LAZY_FAULTS: AtomicUsize = AtomicUsize::new(0);
+// write faults resolved by copying a shared page
+static COW_FAULTS: AtomicUsize = AtomicUsize::new(0);
+
+// called by `memory::init_kernel_memory`, registers the regions set up by the bootloader
+pub(super) fn init(mapper: &mut OffsetPageTable<'static>, frame_allocator: &BitmapFrameAllocator) {
+    let level_4_table = mapper.level_4_table();
+    let first_entry = (DYNAMIC_START >> 39) as usize;
+    let last_entry = ((DYNAMIC_END - 1) >> 39) as usize;
+    assert!(
+        level_4_table.iter().skip(first_entry).take(last_entry - first_entry + 1).all(|e| e.is_unused()),
+        "the dynamic region window is already mapped"
+    );
+
+    let physical_memory_size = align_up(frame_allocator.physical_memory_end().as_u64(), PAGE_SIZE);
+    let offset = mapper.phys_offset();
+    reserve_region("physical memory", RegionKind::PhysicalMemory, Backing::Reserved, offset, physical_memory_size)
+        .expect("physical memory mapping overlaps another region");
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) & !(align - 1)
+}
+
+fn check_range(start: VirtAddr, size: u64) -> Result<(), VmmError> {
+    if size == 0 || !start.is_aligned(PAGE_SIZE) || !size.is_multiple_of(PAGE_SIZE) {
+        return Err(VmmError::InvalidRange);
+    }
+    Ok(())
+}
+
+fn reserve_region(
+    name: &'static str,
+    kind: RegionKind,
+    backing: Backing,
+    start: VirtAddr,
+    size: u64,
+    ) -> Result<Region, VmmError> {
+        check_range(start, size)?;
+        let region = Region { name, kind, backing, start, size };
+        REGIONS.lock().insert(region)?;
+        Ok(region)
+}
+
+// picks a free range of the dynamic window for the region
+fn reserve_dynamic(
+    name: &'static str,
+    kind: RegionKind,
+    backing: Backing,
+    size: u64,
+    align: u64,
+    ) -> Result<Region, VmmError> {
+    let size = align_up(size, PAGE_SIZE);
+    let mut regions = REGIONS.lock();
+    let start = regions.find_free(size, align).ok_or(VmmError::OutOfVirtualSpace)?;
+    check_range(start, size)?;
+    let region = Region { name, kind, backing, start, size };
+    regions.insert(region)?;
+    Ok(region)
+}
+
+// reserves the address range starting at `start`, nothing is mapped
+pub fn reserve_at(name: &'static str, kind: RegionKind, start: VirtAddr, size: u64) -> Result<Region, VmmError> {
+    reserve_region(name, kind, Backing::Reserved, start, align_up(size, PAGE_SIZE))
+}
+
+// reserves `size` bytes anywhere in the dynamic window, nothing is mapped
+pub fn reserve(name: &'static str, kind: RegionKind, size: u64) -> Result<Region, VmmError> {
+    reserve_dynamic(name, kind, Backing::Reserved, size, PAGE_SIZE)
+}
+
+// alignment that lets a region of `size` bytes use the largest possible pages
+// `physical` is the start of the backing memory, if it is fixed
+fn large_page_align(size: u64, physical: Option<PhysAddr>) -> u64 {
+    let fits = |page_size: u64| size >= page_size && physical.is_none_or(|p| p.is_aligned(page_size));
+    if gigantic_pages_supported() && fits(Size1GiB::SIZE) {
+        Size1GiB::SIZE
+    } else if fits(Size2MiB::SIZE) {
+        Size2MiB::SIZE
+    } else {
+        PAGE_SIZE
+    }
+}
+
+// reserves `size` bytes and maps them to fresh frames
+// large regions are mapped with 2 MiB or 1 GiB pages as far as large frames are available
+pub fn allocate(name: &'static str, kind: RegionKind, size: u64, flags: PageTableFlags) -> Result<Region, VmmError> {
+    let region = reserve_dynamic(name, kind, Backing::Allocated, size, large_page_align(size, None))?;
+    let mapped = with_kernel_memory(|mapper, frame_allocator| {
+        map_range(region.start, region.size, None, flags, mapper, frame_allocator)
+    });
+    if let Err(error) = mapped {
+        REGIONS.lock().remove(region.start);
+        return Err(error);
+    }
+    Ok(region)
+}
+
+// reserves `size` bytes plus a guard page below them and maps everything but the guard page
+// the returned region includes the guard page
+pub fn allocate_guarded(
+    name: &'static str,
+    kind: RegionKind,
+    size: u64,
+    flags: PageTableFlags,
+    ) -> Result<Region, VmmError> {
+        let region = reserve_dynamic(name, kind, Backing::Guarded, align_up(size, PAGE_SIZE) + PAGE_SIZE, PAGE_SIZE)?;
+        let mapped = with_kernel_memory(|mapper, frame_allocator| {
+            map_range(region.start + PAGE_SIZE, region.size - PAGE_SIZE, None, flags, mapper, frame_allocator)
+        });
+        if let Err(error) = mapped {
+            REGIONS.lock().remove(region.start);
+            return Err(error);
+        }
+        Ok(region)
+}
+
+// reserves `size` bytes that get frames on first touch, see `handle_page_fault`
+pub fn reserve_lazy(name: &'static str, kind: RegionKind, size: u64, flags: PageTableFlags) -> Result<Region, VmmError> {
+    reserve_dynamic(name, kind, Backing::Lazy(flags), size, PAGE_SIZE)
+}
+
+// maps a new region to the frames of the allocated region starting at `source_start`
+// both regions are read only until they are written, the writing page gets its own copy of the frame
+// regions mapped with 2 MiB or 1 GiB pages can't be shared
+pub fn share_copy_on_write(source_start: VirtAddr, name: &'static str) -> Result<Region, VmmError> {
+    let source = find(source_start).filter(|r| r.start == source_start).ok_or(VmmError::NotFound)?;
+    if source.backing != Backing::Allocated {
+        return Err(VmmError::InvalidRange);
+    }
+    let region = reserve_dynamic(name, source.kind, Backing::Allocated, source.size, PAGE_SIZE)?;
+    let shared = with_kernel_memory(|mapper, frame_allocator| {
+        for (source_page, target_page) in source.pages().zip(region.pages()) {
+            if let Err(error) = cow::share_page(mapper, frame_allocator, source_page, target_page) {
+                // drops the references taken so far
+                unmap_range(region.start, target_page.start_address(), true, mapper, frame_allocator);
+                return Err(error);
+            }
+        }
+        Ok(())
+    });
+    if let Err(error) = shared {
+        REGIONS.lock().remove(region.start);
+        return Err(error);
+    }
+    Ok(region)
+}
+
+// maps `size` bytes of physical memory starting at `physical_start`, e.g. the registers of a device
+// MMIO regions are mapped uncached
+pub fn map_physical(
+    name: &'static str,
+    kind: RegionKind,
+    physical_start: PhysAddr,
+    size: u64,
+    mut flags: PageTableFlags,
+    ) -> Result<Region, VmmError> {
+        if !physical_start.is_aligned(PAGE_SIZE) {
+            return Err(VmmError::InvalidRange);
+        }
+        if kind == RegionKind::Mmio {
+            flags |= PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+        }
+        let align = large_page_align(size, Some(physical_start));
+        let region = reserve_dynamic(name, kind, Backing::Physical(physical_start), size, align)?;
+        let mapped = with_kernel_memory(|mapper, frame_allocator| {
+            map_range(region.start, region.size, Some(physical_start), flags, mapper, frame_allocator)
+        });
+        if let Err(error) = mapped {
+            REGIONS.lock().remove(region.start);
+            return Err(error);
+        }
+        Ok(region)
+}
+
+// removes the region starting at `start`, its pages are unmapped unless the region was only reserved
+// frames allocated by the vmm go back to the frame allocator
+pub fn release(start: VirtAddr) -> Result<Region, VmmError> {
+    let region = REGIONS.lock().remove(start).ok_or(VmmError::NotFound)?;
+    let free_frames = match region.backing {
+        Backing::Reserved => return Ok(region),
+        // pages that are not mapped (guard page, untouched lazy pages) are skipped
+        Backing::Allocated | Backing::Guarded | Backing::Lazy(_) => true,
+        Backing::Physical(_) => false,
+    };
+    with_kernel_memory(|mapper, frame_allocator| {
+        unmap_range(region.start, region.end(), free_frames, mapper, frame_allocator)
+    });
+    Ok(region)
+}
+
+// called by the page fault handler, maps a zeroed frame if `address` is in a lazy region
+// and copies the page on a write to a copy on write page
+// returns false if the fault has to be treated as fatal
+// only try locks are taken, a fault while the vmm or the kernel memory is locked stays fatal
+pub fn handle_page_fault(address: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    let page = Page::<Size4KiB>::containing_address(address);
+
+    // the page is present, only a write to a copy on write page can be resolved
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        if !error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            return false;
+        }
+        // the faulting page is mapped in the active address space, which need not be the kernel's
+        let copied = try_with_kernel_memory(|_, frame_allocator| {
+            cow::handle_write_fault(&mut address_space::active_mapper(), frame_allocator, page)
+        });
+        if copied == Some(true) {
+            COW_FAULTS.fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+        return false;
+    }
+    let flags = match REGIONS.try_lock().and_then(|regions| regions.iter().find(|r| r.contains(address))) {
+        Some(Region { backing: Backing::Lazy(flags), .. }) => flags,
+        _ => return false,
+    };
+
+    let flags = protection::enforce(flags | PageTableFlags::PRESENT);
+    let mapped = try_with_kernel_memory(|mapper, frame_allocator| {
+        let frame = frame_allocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+        let frame_ptr: *mut u8 = (mapper.phys_offset() + frame.start_address().as_u64()).as_mut_ptr();
+        unsafe {
+            frame_ptr.write_bytes(0, PAGE_SIZE as usize);
+            match mapper.map_to_with_table_flags(page, frame, flags, cow::TABLE_FLAGS, frame_allocator) {
+                Ok(flush) => flush.flush(),
+                Err(error) => {
+                    frame_allocator.deallocate_frame(frame);
+                    return Err(error);
+                }
+            }
+        }
+        Ok(())
+    });
+    let resolved = matches!(mapped, Some(Ok(())));
+    if resolved {
+        LAZY_FAULTS.fetch_add(1, Ordering::Relaxed);
+    }
+    resolved
+}
+
+// number of page faults that mapped a frame into a lazy region
+pub fn lazy_faults() -> usize {
+    LAZY_FAULTS.load(Ordering::Relaxed)
+}
+
+// number of write faults that copied a shared page
+pub fn cow_faults() -> usize {
+    COW_FAULTS.load(Ordering::Relaxed)
+}
+
+// the region containing `address`
+pub fn find(address: VirtAddr) -> Option<Region> {
+    REGIONS.lock().iter().find(|r| r.contains(address))
+}
+
+// copy of the current layout
+pub fn regions() -> RegionList {
+    *REGIONS.lock()
+}
+
+// prints the current layout over serial
+pub fn dump_layout() {
+    let regions = regions();
+    serial_println!("virtual memory layout ({} regions):", regions.len());
+    for region in regions.iter() {
+        serial_println!("  {}", region);
+    }
+}
+
+// maps fresh frames to `start..start + size`, used by the heap to grow
+pub(crate) fn map_fresh(
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BitmapFrameAllocator,
+    ) -> Result<(), VmmError> {
+        map_range(start, size, None, flags, mapper, frame_allocator)
+}
+
+// maps `start..start + size` to the physical memory at `physical` or to fresh frames
+// every page is as large as the alignment and the remaining size allow, fresh large pages fall
+// back to smaller ones when no large frame is free
+// on failure the pages mapped so far are unmapped again
+fn map_range(
+    start: VirtAddr,
+    size: u64,
+    physical: Option<PhysAddr>,
+    flags: PageTableFlags,
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BitmapFrameAllocator,
+    ) -> Result<(), VmmError> {
+        let flags = protection::enforce(flags | PageTableFlags::PRESENT);
+        let end = start + size;
+        let mut address = start;
+        while address < end {
+            let target = physical.map(|physical| physical + (address - start));
+            match map_largest_page(address, end, target, flags, mapper, frame_allocator) {
+                Ok(page_size) => address += page_size,
+                Err(error) => {
+                    unmap_range(start, address, physical.is_none(), mapper, frame_allocator);
+                    return Err(error);
+                }
+            }
+        }
+        Ok(())
+}
+
+// maps a single page at `address`, returns the size of the page
+fn map_largest_page(
+    address: VirtAddr,
+    end: VirtAddr,
+    physical: Option<PhysAddr>,
+    flags: PageTableFlags,
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BitmapFrameAllocator,
+    ) -> Result<u64, VmmError> {
+        let fits = |page_size: u64| {
+            address.is_aligned(page_size)
+                && end - address >= page_size
+                && physical.is_none_or(|p| p.is_aligned(page_size))
+        };
+
+        if gigantic_pages_supported() && fits(Size1GiB::SIZE) {
+            if let Some(frame) = large_frame::<Size1GiB>(physical, frame_allocator) {
+                map_page(Page::containing_address(address), frame, physical.is_none(), flags, mapper, frame_allocator)?;
+                return Ok(Size1GiB::SIZE);
+            }
+        }
+        if fits(Size2MiB::SIZE) {
+            if let Some(frame) = large_frame::<Size2MiB>(physical, frame_allocator) {
+                map_page(Page::containing_address(address), frame, physical.is_none(), flags, mapper, frame_allocator)?;
+                return Ok(Size2MiB::SIZE);
+            }
+        }
+
+        let frame = match physical {
+            Some(physical) => PhysFrame::containing_address(physical),
+            None => frame_allocator.allocate_frame().ok_or(VmmError::FrameAllocationFailed)?,
+        };
+        map_page(Page::<Size4KiB>::containing_address(address), frame, physical.is_none(), flags, mapper, frame_allocator)?;
+        Ok(Size4KiB::SIZE)
+}
+
+fn large_frame<S: PageSize>(physical: Option<PhysAddr>, frame_allocator: &mut BitmapFrameAllocator) -> Option<PhysFrame<S>> {
+    match physical {
+        Some(physical) => Some(PhysFrame::containing_address(physical)),
+        None => frame_allocator.allocate_large_frame(),
+    }
+}
+
+// a fresh frame goes back to the frame allocator if it can't be mapped
+fn map_page<S: PageSize>(
+    page: Page<S>,
+    frame: PhysFrame<S>,
+    fresh: bool,
+    flags: PageTableFlags,
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BitmapFrameAllocator,
+    ) -> Result<(), VmmError>
+    where
+        OffsetPageTable<'static>: Mapper<S>,
+    {
+        match unsafe {mapper.map_to_with_table_flags(page, frame, flags, cow::TABLE_FLAGS, frame_allocator)} {
+            Ok(flush) => {
+                flush.flush();
+                Ok(())
+            }
+            Err(error) => {
+                if fresh {
+                    unsafe {frame_allocator.deallocate_large_frame(frame)};
+                }
+                Err(error.into())
+            }
+        }
+}
+
+// unmaps every page in `start..end`, whatever its size
+// pages that were never mapped are skipped
+fn unmap_range(
+    start: VirtAddr,
+    end: VirtAddr,
+    free_frames: bool,
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BitmapFrameAllocator,
+    ) {
+        let mut address = start;
+        while address < end {
+            address += match mapper.translate(address) {
+                TranslateResult::Mapped { frame: MappedFrame::Size4KiB(_), .. } =>
+                    unmap_page::<Size4KiB>(address, free_frames, mapper, frame_allocator),
+                TranslateResult::Mapped { frame: MappedFrame::Size2MiB(_), .. } =>
+                    unmap_page::<Size2MiB>(address, free_frames, mapper, frame_allocator),
+                TranslateResult::Mapped { frame: MappedFrame::Size1GiB(_), .. } =>
+                    unmap_page::<Size1GiB>(address, free_frames, mapper, frame_allocator),
+                _ => Size4KiB::SIZE,
+            };
+        }
+}
+
+// returns the size of the page
+fn unmap_page<S: PageSize>(
+    address: VirtAddr,
+    free_frame: bool,
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BitmapFrameAllocator,
+    ) -> u64
+    where
+        OffsetPageTable<'static>: Mapper<S>,
+    {
+        if let Ok((frame, flush)) = mapper.unmap(Page::<S>::containing_address(address)) {
+            flush.flush();
+            if free_frame {
+                unsafe {frame_allocator.deallocate_large_frame(frame)};
+            }
+        }
+        S::SIZE
+}
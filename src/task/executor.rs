@@ -6,9 +6,13 @@ use alloc::{
     sync::Arc,
     task::Wake
 };
+use crate::allocator::slab::{ObjectCache, SlabBox};
+
+// spawned tasks live in their own slab cache, only their futures are boxed
+static TASKS: ObjectCache<Task> = ObjectCache::without_constructor("task");
 
 pub struct Executor {
-    tasks: BTreeMap<TaskId, Task>,
+    tasks: BTreeMap<TaskId, SlabBox<Task>>,
     task_queue: Arc<ArrayQueue<TaskId>>,
     waker_cache: BTreeMap<TaskId, Waker>
 }
@@ -41,6 +45,7 @@ impl Executor {
 
     pub fn spawn(&mut self, task:Task) {
         let task_id = task.id;
+        let task = TASKS.alloc_with(task).expect("out of memory");
         if self.tasks.insert(task_id, task).is_some() {
             panic!("task with same ID already exists in tasks");
         }
@@ -12,6 +12,9 @@ use alloc::boxed::Box;
 pub mod simple_executor;
 pub mod keyboard;
 pub mod executor;
+pub mod timer;
+
+pub use timer::{sleep, sleep_until, timeout, interval, Sleep, Timeout, Interval, Elapsed};
 
 
 
@@ -0,0 +1,219 @@
+use core::{
+    cmp::{Ordering, Reverse},
+    future::Future,
+    pin::Pin,
+    sync::atomic::{self, AtomicU64},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+use alloc::collections::BinaryHeap;
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::Stream;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::time;
+
+// a waker that is due at `deadline`, the id tells the timers of equal deadlines apart
+struct Entry {
+    deadline: Duration,
+    id: u64,
+    waker: Waker,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        (self.deadline, self.id) == (other.deadline, other.id)
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.deadline, self.id).cmp(&(other.deadline, other.id))
+    }
+}
+
+// the earliest deadline is on top
+// tasks lock it with interrupts disabled, the timer interrupt only tries the lock
+static TIMERS: Mutex<BinaryHeap<Reverse<Entry>>> = Mutex::new(BinaryHeap::new());
+// expired entries are handed back to task context, the interrupt must not free their wakers
+static EXPIRED: OnceCell<ArrayQueue<Entry>> = OnceCell::uninit();
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+// used by the timer interrupt handler, must not block or allocate
+pub(crate) fn wake_expired() {
+    let (Ok(expired), Some(mut timers)) = (EXPIRED.try_get(), TIMERS.try_lock()) else {
+        return;
+    };
+    let now = time::uptime();
+    while timers.peek().is_some_and(|Reverse(entry)| entry.deadline <= now) {
+        if expired.is_full() {
+            // woken again on the next tick, once the queue was drained
+            if let Some(Reverse(entry)) = timers.peek() {
+                entry.waker.wake_by_ref();
+            }
+            break;
+        }
+        if let Some(Reverse(entry)) = timers.pop() {
+            entry.waker.wake_by_ref();
+            let _ = expired.push(entry);
+        }
+    }
+}
+
+// drops the wakers of expired entries in task context
+fn drain_expired() {
+    if let Ok(expired) = EXPIRED.try_get() {
+        while expired.pop().is_ok() {}
+    }
+}
+
+// timers that are registered and not expired yet
+pub fn pending_timers() -> usize {
+    interrupts::without_interrupts(|| TIMERS.lock().len())
+}
+
+fn register(deadline: Duration, id: u64, waker: &Waker) {
+    EXPIRED.init_once(|| ArrayQueue::new(256));
+    drain_expired();
+    interrupts::without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        timers.retain(|Reverse(entry)| entry.id != id);
+        timers.push(Reverse(Entry { deadline, id, waker: waker.clone() }));
+    });
+}
+
+fn unregister(id: u64) {
+    interrupts::without_interrupts(|| TIMERS.lock().retain(|Reverse(entry)| entry.id != id));
+    drain_expired();
+}
+
+// completes once the uptime reached its deadline, at most one tick after it
+pub struct Sleep {
+    deadline: Duration,
+    id: u64,
+    registered: bool,
+}
+
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(time::uptime() + duration)
+}
+
+// `deadline` is an uptime
+pub fn sleep_until(deadline: Duration) -> Sleep {
+    Sleep {
+        deadline,
+        id: NEXT_ID.fetch_add(1, atomic::Ordering::Relaxed),
+        registered: false,
+    }
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> Duration {
+        self.deadline
+    }
+
+    fn reset(&mut self, deadline: Duration) {
+        self.deadline = deadline;
+        if self.registered {
+            unregister(self.id);
+            self.registered = false;
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if time::uptime() >= self.deadline {
+            return Poll::Ready(());
+        }
+        register(self.deadline, self.id, cx.waker());
+        self.registered = true;
+
+        // the deadline may have passed before the entry was in the queue
+        if time::uptime() >= self.deadline {
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if self.registered {
+            unregister(self.id);
+        }
+    }
+}
+
+// the future did not complete in time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+
+// runs `future` until it completes or `duration` has passed, the future is dropped then
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout { future, sleep: sleep(duration) }
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // the future is never moved out of the pinned timeout, `Sleep` doesn't need pinning
+        let Timeout { future, sleep } = unsafe {self.get_unchecked_mut()};
+        if let Poll::Ready(output) = unsafe {Pin::new_unchecked(future)}.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        Pin::new(sleep).poll(cx).map(|()| Err(Elapsed))
+    }
+}
+
+// yields the scheduled uptime of every period, the first one a period after it was created
+// periods that were missed are skipped instead of being yielded in a burst
+pub struct Interval {
+    period: Duration,
+    sleep: Sleep,
+}
+
+pub fn interval(period: Duration) -> Interval {
+    assert!(!period.is_zero(), "the period of an interval must not be zero");
+    Interval { period, sleep: sleep(period) }
+}
+
+impl Interval {
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+}
+
+impl Stream for Interval {
+    type Item = Duration;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Duration>> {
+        let Interval { period, sleep } = &mut *self;
+        if Pin::new(&mut *sleep).poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        let scheduled = sleep.deadline();
+        let mut next = scheduled + *period;
+        let now = time::uptime();
+        if next <= now {
+            next = now + *period;
+        }
+        sleep.reset(next);
+        Poll::Ready(Some(scheduled))
+    }
+}
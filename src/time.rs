@@ -0,0 +1,285 @@
+use core::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::interrupts::{self, apic, IrqError};
+use crate::serial_println;
+
+pub mod date;
+pub mod hpet;
+pub mod pit;
+pub mod rtc;
+pub mod tsc;
+
+pub use date::DateTime;
+pub use hpet::HpetError;
+pub use tsc::{now_ns, Clock, Stopwatch};
+
+// interrupts per second of the timer, `set_tick_frequency` changes it at runtime
+pub const DEFAULT_TICK_FREQUENCY: u32 = 1000;
+
+// what raises the timer interrupt and drives the uptime
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerSource {
+    // periodic ticks of PIT channel 0, the uptime counts ticks
+    Pit,
+    // periodic interrupts of HPET timer 0, the uptime is read from the HPET counter
+    Hpet,
+    // the periodic local APIC timer, the uptime counts ticks
+    LocalApic,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerError {
+    Hpet(HpetError),
+    // the local APIC timer needs `interrupts::apic::init`
+    ApicDisabled,
+    Irq(IrqError),
+}
+
+impl From<HpetError> for TimerError {
+    fn from(error: HpetError) -> Self {
+        TimerError::Hpet(error)
+    }
+}
+
+impl From<IrqError> for TimerError {
+    fn from(error: IrqError) -> Self {
+        TimerError::Irq(error)
+    }
+}
+
+// timer interrupts since boot
+static TICKS: AtomicU64 = AtomicU64::new(0);
+// uptime when the source or the frequency was last changed, and the ticks since then
+static BASE_NANOS: AtomicU64 = AtomicU64::new(0);
+static TICKS_SINCE_BASE: AtomicU64 = AtomicU64::new(0);
+static SOURCE: AtomicU8 = AtomicU8::new(TimerSource::Pit as u8);
+// period of the HPET and local APIC timer interrupts, the PIT knows its own
+static PERIOD_NANOS: AtomicU64 = AtomicU64::new(0);
+// HPET counter in nanoseconds when it became the source
+static HPET_BASE_NANOS: AtomicU64 = AtomicU64::new(0);
+// UNIX timestamp of the RTC when the clock started, the uptime counts from there
+static BOOT_TIMESTAMP: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) fn init() {
+    BOOT_TIMESTAMP.store(rtc::read().unix_timestamp(), Ordering::Relaxed);
+    pit::set_frequency(DEFAULT_TICK_FREQUENCY);
+
+    let frequency = tsc::calibrate();
+    let invariant = if tsc::is_invariant() { "invariant" } else { "not invariant" };
+    serial_println!("tsc: {} MHz, {}, timestamps from {:?}", frequency / 1_000_000, invariant, tsc::clock());
+}
+
+// maps the HPET and makes it the reference of the TSC, with the `hpet-timer` feature it becomes
+// the timer source as well
+// needs the kernel memory to map the registers, the timer source stays as it is on errors
+pub fn init_hpet() -> Result<(), TimerError> {
+    let result = hpet::init().map_err(TimerError::from).and_then(|()| {
+        let frequency = tsc::calibrate();
+        serial_println!("hpet: {} MHz, tsc: {} MHz", hpet::frequency() / 1_000_000, frequency / 1_000_000);
+        if cfg!(feature = "hpet-timer") {
+            set_timer_source(TimerSource::Hpet)?;
+        }
+        Ok(())
+    });
+    if let Err(error) = result {
+        serial_println!("hpet: {:?}, the timer source is {:?}", error, timer_source());
+    }
+    result
+}
+
+pub fn timer_source() -> TimerSource {
+    match SOURCE.load(Ordering::Relaxed) {
+        source if source == TimerSource::Hpet as u8 => TimerSource::Hpet,
+        source if source == TimerSource::LocalApic as u8 => TimerSource::LocalApic,
+        _ => TimerSource::Pit,
+    }
+}
+
+// hands the timer interrupt and the uptime over to `source` at the current tick frequency,
+// the uptime keeps counting from where it is; on error the old source keeps running
+pub fn set_timer_source(source: TimerSource) -> Result<(), TimerError> {
+    let previous = timer_source();
+    if source == previous {
+        return Ok(());
+    }
+    let frequency = tick_frequency();
+    without_interrupts(|| {
+        let uptime = uptime_nanos();
+        stop_source(previous)?;
+        if let Err(error) = start_source(source, frequency) {
+            start_source(previous, frequency)?;
+            return Err(error);
+        }
+        if source == TimerSource::Hpet {
+            HPET_BASE_NANOS.store(hpet::now_ns(), Ordering::Relaxed);
+        }
+        BASE_NANOS.store(uptime, Ordering::Relaxed);
+        TICKS_SINCE_BASE.store(0, Ordering::Relaxed);
+        SOURCE.store(source as u8, Ordering::Relaxed);
+        Ok(())
+    })
+}
+
+// the PIT keeps counting, its line is masked or taken by the HPET
+fn stop_source(source: TimerSource) -> Result<(), TimerError> {
+    match source {
+        TimerSource::Pit => {}
+        TimerSource::Hpet => {
+            hpet::stop(hpet::TIMER_0)?;
+            hpet::set_legacy_replacement(false)?;
+        }
+        TimerSource::LocalApic => apic::stop_timer(),
+    }
+    Ok(())
+}
+
+// returns the frequency the source really runs at
+fn start_source(source: TimerSource, frequency: u32) -> Result<u32, TimerError> {
+    match source {
+        TimerSource::Pit => {
+            let frequency = pit::set_frequency(frequency);
+            interrupts::route_timer(interrupts::TIMER_IRQ)?;
+            Ok(frequency)
+        }
+        TimerSource::Hpet => {
+            if !hpet::has_64_bit_counter()? {
+                return Err(HpetError::Counter32Bit.into());
+            }
+            hpet::set_legacy_replacement(true)?;
+            let started = start_hpet_timer(frequency).and_then(|frequency| {
+                interrupts::route_timer(interrupts::TIMER_IRQ)?;
+                Ok(frequency)
+            });
+            if started.is_err() {
+                let _ = hpet::stop(hpet::TIMER_0);
+                hpet::set_legacy_replacement(false)?;
+            }
+            started
+        }
+        TimerSource::LocalApic => {
+            let frequency = start_local_apic_timer(frequency)?;
+            if let Err(error) = interrupts::route_timer(interrupts::LAPIC_TIMER_IRQ) {
+                apic::stop_timer();
+                return Err(error.into());
+            }
+            Ok(frequency)
+        }
+    }
+}
+
+fn store_period(period: Duration) -> u32 {
+    PERIOD_NANOS.store(period.as_nanos() as u64, Ordering::Relaxed);
+    (1_000_000_000 / period.as_nanos().max(1)) as u32
+}
+
+fn start_hpet_timer(frequency: u32) -> Result<u32, TimerError> {
+    let period = hpet::periodic(hpet::TIMER_0, Duration::from_secs(1) / frequency.max(1))?;
+    Ok(store_period(period))
+}
+
+fn start_local_apic_timer(frequency: u32) -> Result<u32, TimerError> {
+    let period = apic::start_timer(frequency).ok_or(TimerError::ApicDisabled)?;
+    Ok(store_period(period))
+}
+
+// called from the timer interrupt
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    TICKS_SINCE_BASE.fetch_add(1, Ordering::Relaxed);
+}
+
+// monotonic count of timer interrupts since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+pub fn tick_frequency() -> u32 {
+    match timer_source() {
+        TimerSource::Pit => pit::frequency(),
+        TimerSource::Hpet | TimerSource::LocalApic => (1_000_000_000 / PERIOD_NANOS.load(Ordering::Relaxed).max(1)) as u32,
+    }
+}
+
+// reprograms the timer source, the uptime keeps counting from where it is
+// returns the frequency the timer really runs at
+pub fn set_tick_frequency(frequency: u32) -> u32 {
+    without_interrupts(|| {
+        let source = timer_source();
+        // the HPET uptime comes from the counter, it doesn't depend on the period
+        if source != TimerSource::Hpet {
+            BASE_NANOS.store(uptime_nanos(), Ordering::Relaxed);
+            TICKS_SINCE_BASE.store(0, Ordering::Relaxed);
+        }
+        match source {
+            TimerSource::Pit => pit::set_frequency(frequency),
+            TimerSource::Hpet => start_hpet_timer(frequency).unwrap_or_else(|_| tick_frequency()),
+            TimerSource::LocalApic => start_local_apic_timer(frequency).unwrap_or_else(|_| tick_frequency()),
+        }
+    })
+}
+
+fn uptime_nanos() -> u64 {
+    without_interrupts(|| {
+        let ticks = TICKS_SINCE_BASE.load(Ordering::Relaxed);
+        let since_base = match timer_source() {
+            TimerSource::Pit => ticks * pit::period_nanos(),
+            TimerSource::Hpet => hpet::now_ns() - HPET_BASE_NANOS.load(Ordering::Relaxed),
+            TimerSource::LocalApic => ticks * PERIOD_NANOS.load(Ordering::Relaxed),
+        };
+        BASE_NANOS.load(Ordering::Relaxed) + since_base
+    })
+}
+
+// time since boot, with the resolution of one tick on the PIT and of the counter on the HPET
+pub fn uptime() -> Duration {
+    Duration::from_nanos(uptime_nanos())
+}
+
+// wall clock time since 1970-01-01 00:00:00 UTC
+// the RTC is read once at boot and only has seconds, the sub second part comes from the uptime
+pub fn now() -> Duration {
+    Duration::from_secs(BOOT_TIMESTAMP.load(Ordering::Relaxed)) + uptime()
+}
+
+pub fn date() -> DateTime {
+    DateTime::from_unix_timestamp(now().as_secs())
+}
+
+#[test_case]
+fn uptime_is_monotonic() {
+    let before = uptime();
+    let ticks = ticks();
+    while self::ticks() < ticks + 2 {
+        x86_64::instructions::hlt();
+    }
+    assert!(uptime() > before);
+}
+
+#[test_case]
+fn ticks_follow_the_frequency() {
+    // channel 2 measures 50 ms independently of the interrupts
+    let start = ticks();
+    pit::busy_wait(Duration::from_millis(50));
+    let elapsed = ticks() - start;
+    let expected = u64::from(tick_frequency()) / 20;
+    assert!(elapsed.abs_diff(expected) <= 2, "{} ticks in 50 ms, expected {}", elapsed, expected);
+}
+
+#[test_case]
+fn changing_the_frequency_keeps_the_uptime() {
+    let before = uptime();
+    set_tick_frequency(100);
+    assert_eq!(tick_frequency(), 100);
+    assert!(uptime() >= before);
+    set_tick_frequency(DEFAULT_TICK_FREQUENCY);
+    assert!(uptime() >= before);
+}
+
+#[test_case]
+fn now_follows_the_rtc() {
+    // the RTC may tick over to the next second while it is read
+    let rtc = rtc::read().unix_timestamp();
+    assert!(now().as_secs().abs_diff(rtc) <= 1, "now is {:?}, the rtc says {}", now(), rtc);
+}
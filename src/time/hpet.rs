@@ -0,0 +1,285 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::PageTableFlags;
+use spin::Once;
+
+use crate::acpi;
+use crate::memory::vmm::{self, RegionKind, VmmError};
+
+// general registers, offsets from the base
+const CAPABILITIES: u64 = 0x000;
+const CONFIGURATION: u64 = 0x010;
+const MAIN_COUNTER: u64 = 0x0f0;
+
+// capabilities, the counter period in femtoseconds is in the upper half
+const CAPABILITY_TIMERS_SHIFT: u64 = 8;
+const CAPABILITY_COUNTER_64_BIT: u64 = 1 << 13;
+const CAPABILITY_LEGACY_REPLACEMENT: u64 = 1 << 15;
+
+const CONFIGURATION_ENABLE: u64 = 1 << 0;
+const CONFIGURATION_LEGACY_REPLACEMENT: u64 = 1 << 1;
+
+// timer registers, every timer has 0x20 bytes after 0x100
+const fn timer_configuration(timer: u8) -> u64 {
+    0x100 + 0x20 * timer as u64
+}
+
+const fn timer_comparator(timer: u8) -> u64 {
+    0x108 + 0x20 * timer as u64
+}
+
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+// the next write to the comparator of a periodic timer sets the comparator instead of the period
+const TIMER_VALUE_SET: u64 = 1 << 6;
+// the comparator only holds 32 bits, the counter wraps for it after 2^32 ticks
+const TIMER_32_BIT: u64 = 1 << 8;
+
+// in legacy replacement mode timer 0 takes the place of the PIT and timer 1 of the RTC
+pub const TIMER_0: u8 = 0;
+pub const TIMER_1: u8 = 1;
+pub const TIMER_0_IRQ: u8 = 0;
+pub const TIMER_1_IRQ: u8 = 8;
+
+const FEMTOSECONDS_PER_NANOSECOND: u64 = 1_000_000;
+// the specification allows counter periods up to 100 ns
+const MAXIMUM_PERIOD_FEMTOSECONDS: u64 = 0x05f5_e100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError {
+    // the ACPI tables don't describe an HPET
+    NotFound,
+    NotInitialized,
+    // the counter period is zero or longer than 100 ns
+    InvalidPeriod,
+    // the main counter only has 32 bits, it wraps too often to keep the uptime
+    Counter32Bit,
+    Map(VmmError),
+    NoLegacyReplacement,
+    // the timer doesn't exist or isn't routed in legacy replacement mode
+    InvalidTimer,
+    NotPeriodic,
+    // zero, or longer than the 32 bit comparator can count
+    InvalidDuration,
+}
+
+impl From<VmmError> for HpetError {
+    fn from(error: VmmError) -> Self {
+        HpetError::Map(error)
+    }
+}
+
+struct Hpet {
+    base: VirtAddr,
+    // length of one counter tick
+    period_femtoseconds: u64,
+    timers: u8,
+    minimum_tick: u64,
+    counter_64_bit: bool,
+}
+
+impl Hpet {
+    fn read(&self, register: u64) -> u64 {
+        unsafe {(self.base + register).as_ptr::<u64>().read_volatile()}
+    }
+
+    fn write(&self, register: u64, value: u64) {
+        unsafe {(self.base + register).as_mut_ptr::<u64>().write_volatile(value)};
+    }
+
+    fn write_32(&self, register: u64, value: u32) {
+        unsafe {(self.base + register).as_mut_ptr::<u32>().write_volatile(value)};
+    }
+
+    // a 32 bit main counter is extended to 64 bits in software, which only works as long as it is
+    // read at least once per wrap, every 5 minutes at 14.3 MHz
+    fn counter(&self) -> u64 {
+        let value = self.read(MAIN_COUNTER);
+        if self.counter_64_bit {
+            return value;
+        }
+        let last = EXTENDED_COUNTER.load(Ordering::Relaxed);
+        let mut extended = (last & !u64::from(u32::MAX)) | (value & u64::from(u32::MAX));
+        if extended < last {
+            extended += 1 << 32;
+        }
+        EXTENDED_COUNTER.fetch_max(extended, Ordering::Relaxed);
+        extended
+    }
+
+    fn ticks(&self, duration: Duration) -> u64 {
+        (duration.as_nanos() * u128::from(FEMTOSECONDS_PER_NANOSECOND) / u128::from(self.period_femtoseconds)) as u64
+    }
+
+    fn duration(&self, ticks: u64) -> Duration {
+        Duration::from_nanos((u128::from(ticks) * u128::from(self.period_femtoseconds) / u128::from(FEMTOSECONDS_PER_NANOSECOND)) as u64)
+    }
+}
+
+static HPET: Once<Hpet> = Once::new();
+static LEGACY_REPLACEMENT: AtomicBool = AtomicBool::new(false);
+// last value of a 32 bit main counter, with the wraps in the upper half
+static EXTENDED_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+fn hpet() -> Result<&'static Hpet, HpetError> {
+    HPET.r#try().ok_or(HpetError::NotInitialized)
+}
+
+pub fn is_initialized() -> bool {
+    HPET.r#try().is_some()
+}
+
+// maps the HPET found in the ACPI tables and starts its main counter
+// the timers stay off, the PIT keeps its interrupt line until `set_legacy_replacement`
+// needs the kernel memory to map the registers
+pub fn init() -> Result<(), HpetError> {
+    if is_initialized() {
+        return Ok(());
+    }
+    let info = acpi::hpet().ok_or(HpetError::NotFound)?;
+    let region = vmm::map_physical("hpet", RegionKind::Mmio, info.address, 4096, PageTableFlags::WRITABLE)?;
+    let mut hpet = Hpet {
+        base: region.start,
+        period_femtoseconds: 0,
+        timers: 0,
+        minimum_tick: u64::from(info.minimum_tick),
+        counter_64_bit: false,
+    };
+    let capabilities = hpet.read(CAPABILITIES);
+    hpet.period_femtoseconds = match valid_period(capabilities >> 32) {
+        Ok(period) => period,
+        Err(error) => {
+            let _ = vmm::release(region.start);
+            return Err(error);
+        }
+    };
+    hpet.timers = ((capabilities >> CAPABILITY_TIMERS_SHIFT) & 0x1f) as u8 + 1;
+    hpet.counter_64_bit = capabilities & CAPABILITY_COUNTER_64_BIT != 0;
+
+    for timer in 0..hpet.timers {
+        let configuration = hpet.read(timer_configuration(timer));
+        hpet.write(timer_configuration(timer), configuration & !(TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC));
+    }
+    let configuration = hpet.read(CONFIGURATION) & !CONFIGURATION_LEGACY_REPLACEMENT;
+    hpet.write(CONFIGURATION, configuration | CONFIGURATION_ENABLE);
+    HPET.call_once(|| hpet);
+    Ok(())
+}
+
+fn valid_period(femtoseconds: u64) -> Result<u64, HpetError> {
+    if femtoseconds == 0 || femtoseconds > MAXIMUM_PERIOD_FEMTOSECONDS {
+        return Err(HpetError::InvalidPeriod);
+    }
+    Ok(femtoseconds)
+}
+
+// a 32 bit main counter can't be the timer source, it only counts for minutes
+pub fn has_64_bit_counter() -> Result<bool, HpetError> {
+    Ok(hpet()?.counter_64_bit)
+}
+
+// ticks of the main counter per second
+pub fn frequency() -> u64 {
+    hpet().map_or(0, |hpet| 1_000_000_000_000_000 / hpet.period_femtoseconds)
+}
+
+pub fn counter() -> u64 {
+    hpet().map_or(0, Hpet::counter)
+}
+
+// nanoseconds the main counter has been running, monotonic
+// a 32 bit counter has to be read at least once per wrap, see `Hpet::counter`
+pub fn now_ns() -> u64 {
+    hpet().map_or(0, |hpet| hpet.duration(hpet.counter()).as_nanos() as u64)
+}
+
+pub fn busy_wait(duration: Duration) -> Result<(), HpetError> {
+    let hpet = hpet()?;
+    let (start, ticks) = (hpet.counter(), hpet.ticks(duration));
+    while hpet.counter().saturating_sub(start) < ticks {
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+// routes timer 0 to IRQ 0 and timer 1 to IRQ 8, the PIT and the RTC lose their interrupts
+pub fn set_legacy_replacement(enabled: bool) -> Result<(), HpetError> {
+    let hpet = hpet()?;
+    if hpet.read(CAPABILITIES) & CAPABILITY_LEGACY_REPLACEMENT == 0 {
+        return Err(HpetError::NoLegacyReplacement);
+    }
+    let configuration = hpet.read(CONFIGURATION);
+    hpet.write(CONFIGURATION, if enabled {
+        configuration | CONFIGURATION_LEGACY_REPLACEMENT
+    } else {
+        configuration & !CONFIGURATION_LEGACY_REPLACEMENT
+    });
+    LEGACY_REPLACEMENT.store(enabled, Ordering::Relaxed);
+    Ok(())
+}
+
+fn routed_timer(timer: u8) -> Result<&'static Hpet, HpetError> {
+    let hpet = hpet()?;
+    if timer > TIMER_1 || timer >= hpet.timers || !LEGACY_REPLACEMENT.load(Ordering::Relaxed) {
+        return Err(HpetError::InvalidTimer);
+    }
+    Ok(hpet)
+}
+
+fn timer_ticks(hpet: &Hpet, duration: Duration) -> Result<u64, HpetError> {
+    let ticks = hpet.ticks(duration);
+    if ticks == 0 || ticks > u64::from(u32::MAX) {
+        return Err(HpetError::InvalidDuration);
+    }
+    Ok(ticks)
+}
+
+// raises the interrupt of `timer` once after `delay`
+// the 32 bit comparator matches again when the counter wraps, `stop` the timer before that
+pub fn one_shot(timer: u8, delay: Duration) -> Result<(), HpetError> {
+    let hpet = routed_timer(timer)?;
+    let ticks = timer_ticks(hpet, delay)?;
+    let configuration = hpet.read(timer_configuration(timer)) & !TIMER_PERIODIC;
+    hpet.write(timer_configuration(timer), configuration | TIMER_32_BIT | TIMER_INTERRUPT_ENABLE);
+    hpet.write_32(timer_comparator(timer), hpet.read(MAIN_COUNTER).wrapping_add(ticks) as u32);
+    Ok(())
+}
+
+// raises the interrupt of `timer` every `period`, returns the period it really runs with
+pub fn periodic(timer: u8, period: Duration) -> Result<Duration, HpetError> {
+    let hpet = routed_timer(timer)?;
+    let ticks = timer_ticks(hpet, period)?.max(hpet.minimum_tick);
+    let configuration = hpet.read(timer_configuration(timer));
+    if configuration & TIMER_PERIODIC_CAPABLE == 0 {
+        return Err(HpetError::NotPeriodic);
+    }
+    hpet.write(
+        timer_configuration(timer),
+        configuration | TIMER_32_BIT | TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC | TIMER_VALUE_SET,
+    );
+    // the first write sets the comparator, the second one the period
+    hpet.write_32(timer_comparator(timer), hpet.read(MAIN_COUNTER).wrapping_add(ticks) as u32);
+    hpet.write_32(timer_comparator(timer), ticks as u32);
+    Ok(hpet.duration(ticks))
+}
+
+pub fn stop(timer: u8) -> Result<(), HpetError> {
+    let hpet = hpet()?;
+    if timer >= hpet.timers {
+        return Err(HpetError::InvalidTimer);
+    }
+    let configuration = hpet.read(timer_configuration(timer));
+    hpet.write(timer_configuration(timer), configuration & !(TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC));
+    Ok(())
+}
+
+#[test_case]
+fn periods_are_validated() {
+    assert_eq!(valid_period(0), Err(HpetError::InvalidPeriod));
+    assert_eq!(valid_period(MAXIMUM_PERIOD_FEMTOSECONDS + 1), Err(HpetError::InvalidPeriod));
+    // the 14.318 MHz of QEMU and most chipsets
+    assert_eq!(valid_period(69_841_279), Ok(69_841_279));
+    assert_eq!(valid_period(MAXIMUM_PERIOD_FEMTOSECONDS), Ok(MAXIMUM_PERIOD_FEMTOSECONDS));
+}
@@ -0,0 +1,99 @@
+use core::sync::atomic::{AtomicU16, Ordering};
+use core::time::Duration;
+use x86_64::instructions::port::Port;
+
+// input clock of all three channels
+pub const BASE_FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL_0: u16 = 0x40;
+const CHANNEL_2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+// gate of channel 2 in bit 0, speaker in bit 1 and the output of channel 2 in bit 5
+const CHANNEL_2_GATE: u16 = 0x61;
+
+// a divisor of 0 stands for 65536, the frequency after boot
+static DIVISOR: AtomicU16 = AtomicU16::new(0);
+
+fn divisor_value(divisor: u16) -> u64 {
+    if divisor == 0 { 1 << 16 } else { u64::from(divisor) }
+}
+
+// the closest divisor the 16 bit counter can hold
+pub fn divisor_for(frequency: u32) -> u16 {
+    let divisor = (BASE_FREQUENCY + u64::from(frequency) / 2) / u64::from(frequency.max(1));
+    match divisor {
+        0 | 1 => 2,
+        divisor if divisor >= 1 << 16 => 0,
+        divisor => divisor as u16,
+    }
+}
+
+// programs channel 0, the one wired to IRQ 0, as a rate generator
+// returns the frequency it really runs at, the divisor is rounded
+pub fn set_frequency(frequency: u32) -> u32 {
+    let divisor = divisor_for(frequency);
+    let mut command: Port<u8> = Port::new(COMMAND);
+    let mut channel_0: Port<u8> = Port::new(CHANNEL_0);
+    unsafe {
+        // channel 0, low then high byte, mode 2
+        command.write(0b0011_0100);
+        channel_0.write(divisor as u8);
+        channel_0.write((divisor >> 8) as u8);
+    }
+    DIVISOR.store(divisor, Ordering::Relaxed);
+    frequency_of(divisor) as u32
+}
+
+fn frequency_of(divisor: u16) -> u64 {
+    BASE_FREQUENCY / divisor_value(divisor)
+}
+
+pub fn frequency() -> u32 {
+    frequency_of(DIVISOR.load(Ordering::Relaxed)) as u32
+}
+
+// nanoseconds between two interrupts of channel 0
+pub fn period_nanos() -> u64 {
+    divisor_value(DIVISOR.load(Ordering::Relaxed)) * 1_000_000_000 / BASE_FREQUENCY
+}
+
+// busy waits on channel 2, it is not connected to an interrupt line and works with interrupts off
+pub fn busy_wait(duration: Duration) {
+    let mut remaining = duration.as_nanos() as u64 * BASE_FREQUENCY / 1_000_000_000;
+    while remaining > 0 {
+        let count = remaining.min(0xffff);
+        wait_for_count(count as u16);
+        remaining -= count;
+    }
+}
+
+fn wait_for_count(count: u16) {
+    let mut gate: Port<u8> = Port::new(CHANNEL_2_GATE);
+    let mut command: Port<u8> = Port::new(COMMAND);
+    let mut channel_2: Port<u8> = Port::new(CHANNEL_2);
+    unsafe {
+        // gate low and the speaker off while the channel is programmed
+        let control = gate.read() & !0b11;
+        gate.write(control);
+        // channel 2, low then high byte, mode 0: the output goes high at the end of the count
+        command.write(0b1011_0000);
+        channel_2.write(count as u8);
+        channel_2.write((count >> 8) as u8);
+        // the count starts with the rising edge of the gate
+        gate.write(control | 1);
+        while gate.read() & (1 << 5) == 0 {
+            core::hint::spin_loop();
+        }
+        gate.write(control);
+    }
+}
+
+#[test_case]
+fn divisors_are_rounded_and_clamped() {
+    assert_eq!(divisor_for(1000), 1193);
+    assert_eq!(divisor_for(100), 11932);
+    // too slow for 16 bits, the slowest rate is used
+    assert_eq!(divisor_for(10), 0);
+    assert_eq!(frequency_of(0), 18);
+    assert_eq!(divisor_for(u32::MAX), 2);
+}
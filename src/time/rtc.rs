@@ -0,0 +1,109 @@
+use x86_64::instructions::{interrupts, port::Port};
+
+use super::date::DateTime;
+
+const INDEX: u16 = 0x70;
+const DATA: u16 = 0x71;
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+
+// status A, set while the RTC updates its registers
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+// status B, the formats the firmware chose
+const HOURS_24: u8 = 1 << 1;
+const BINARY: u8 = 1 << 2;
+// in 12 hour mode the hours carry the PM flag
+const HOUR_PM: u8 = 1 << 7;
+
+// the century register isn't at the same index on every machine, the RTC is assumed to be
+// in the 21st century
+const CENTURY: u16 = 2000;
+
+fn read_register(register: u8) -> u8 {
+    let mut index: Port<u8> = Port::new(INDEX);
+    let mut data: Port<u8> = Port::new(DATA);
+    // interrupts could select another register in between
+    interrupts::without_interrupts(|| unsafe {
+        index.write(register);
+        data.read()
+    })
+}
+
+fn update_in_progress() -> bool {
+    read_register(STATUS_A) & UPDATE_IN_PROGRESS != 0
+}
+
+// the raw registers in the order of `DateTime`
+fn read_raw() -> [u8; 6] {
+    while update_in_progress() {
+        core::hint::spin_loop();
+    }
+    [YEAR, MONTH, DAY, HOURS, MINUTES, SECONDS].map(read_register)
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value & 0x0f) + (value >> 4) * 10
+}
+
+fn decode([year, month, day, hours, minutes, seconds]: [u8; 6], status_b: u8) -> DateTime {
+    let binary = |value: u8| if status_b & BINARY != 0 { value } else { from_bcd(value) };
+    let mut hour = binary(hours & !HOUR_PM);
+    if status_b & HOURS_24 == 0 {
+        // 12 AM is midnight and 12 PM is noon
+        hour %= 12;
+        if hours & HOUR_PM != 0 {
+            hour += 12;
+        }
+    }
+    DateTime {
+        year: CENTURY + u16::from(binary(year)),
+        month: binary(month),
+        day: binary(day),
+        hour,
+        minute: binary(minutes),
+        second: binary(seconds),
+    }
+}
+
+// reads the date and time of the RTC
+// an update may start right after the update in progress flag was checked, so the registers
+// are read until two reads in a row agree
+pub fn read() -> DateTime {
+    let mut raw = read_raw();
+    loop {
+        let again = read_raw();
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+    decode(raw, read_register(STATUS_B))
+}
+
+#[test_case]
+fn bcd_and_12_hour_values_are_decoded() {
+    // 2024-02-29 01:05:09 PM in BCD
+    let raw = [0x24, 0x02, 0x29, 0x01 | HOUR_PM, 0x05, 0x09];
+    let expected = DateTime { year: 2024, month: 2, day: 29, hour: 13, minute: 5, second: 9 };
+    assert_eq!(decode(raw, 0), expected);
+    assert_eq!(decode([24, 2, 29, 13, 5, 9], BINARY | HOURS_24), expected);
+    // 12 AM
+    assert_eq!(decode([0x24, 0x02, 0x29, 0x12, 0, 0], 0).hour, 0);
+    // 12 PM
+    assert_eq!(decode([24, 2, 29, 12 | HOUR_PM, 0, 0], BINARY).hour, 12);
+}
+
+#[test_case]
+fn the_rtc_holds_a_plausible_date() {
+    let now = read();
+    assert!(now.year >= 2024);
+    assert!((1..=12).contains(&now.month) && (1..=31).contains(&now.day));
+    assert!(now.hour < 24 && now.minute < 60 && now.second < 60);
+}
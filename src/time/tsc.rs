@@ -0,0 +1,149 @@
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+use core::time::Duration;
+
+use super::{hpet, pit};
+
+// how long the TSC is counted against the reference clock
+const CALIBRATION_TIME: Duration = Duration::from_millis(10);
+
+// what `now_ns` reads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clock {
+    // the TSC, once it is calibrated and invariant
+    Tsc,
+    // the HPET counter, when the TSC is not invariant
+    Hpet,
+    // the uptime of the tick clock, before the calibration or without an HPET
+    Uptime,
+}
+
+// ticks per second, 0 until calibrated
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+static CLOCK: AtomicU8 = AtomicU8::new(Clock::Uptime as u8);
+// reading of the clock when it was last calibrated and the timestamp at that moment, `now_ns` counts from there
+static BASE: AtomicU64 = AtomicU64::new(0);
+static BASE_NS: AtomicU64 = AtomicU64::new(0);
+// the largest timestamp handed out so far, keeps `now_ns` monotonic if the TSC isn't
+static LAST_NS: AtomicU64 = AtomicU64::new(0);
+
+pub fn read() -> u64 {
+    unsafe {_rdtsc()}
+}
+
+// cpuid 0x8000_0007 reports in edx bit 8 that the TSC runs at a constant rate in every
+// power state, without it the frequency may change with the core clock
+pub fn is_invariant() -> bool {
+    __cpuid(0x8000_0000).eax >= 0x8000_0007 && __cpuid(0x8000_0007).edx & (1 << 8) != 0
+}
+
+pub fn frequency() -> u64 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+pub fn clock() -> Clock {
+    match CLOCK.load(Ordering::Relaxed) {
+        clock if clock == Clock::Tsc as u8 => Clock::Tsc,
+        clock if clock == Clock::Hpet as u8 => Clock::Hpet,
+        _ => Clock::Uptime,
+    }
+}
+
+// raw reading of `clock`, TSC ticks or nanoseconds
+fn read_clock(clock: Clock) -> u64 {
+    match clock {
+        Clock::Tsc => read(),
+        Clock::Hpet => hpet::now_ns(),
+        Clock::Uptime => super::uptime().as_nanos() as u64,
+    }
+}
+
+// counts TSC ticks against the HPET once it is initialized, against PIT channel 2 before
+// `now_ns` reads the TSC from then on if it is invariant, the HPET or the tick clock otherwise
+// returns the frequency, `now_ns` continues from where it was
+pub fn calibrate() -> u64 {
+    let start = read();
+    if hpet::busy_wait(CALIBRATION_TIME).is_err() {
+        pit::busy_wait(CALIBRATION_TIME);
+    }
+    let ticks = read() - start;
+    let frequency = (u128::from(ticks) * 1_000_000_000 / CALIBRATION_TIME.as_nanos()) as u64;
+
+    let clock = if is_invariant() {
+        Clock::Tsc
+    } else if hpet::is_initialized() {
+        Clock::Hpet
+    } else {
+        Clock::Uptime
+    };
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let now = now_ns();
+        BASE.store(read_clock(clock), Ordering::Relaxed);
+        BASE_NS.store(now, Ordering::Relaxed);
+        FREQUENCY.store(frequency, Ordering::Relaxed);
+        CLOCK.store(clock as u8, Ordering::Relaxed);
+    });
+    frequency
+}
+
+// nanoseconds since boot, with the resolution of the TSC when it is invariant
+// a TSC that changes its rate with the core clock isn't used, the HPET or the tick clock
+// with its resolution of a tick are read instead, see `clock`
+pub fn now_ns() -> u64 {
+    let clock = clock();
+    let elapsed = read_clock(clock).saturating_sub(BASE.load(Ordering::Relaxed));
+    let elapsed_ns = match clock {
+        Clock::Tsc => (u128::from(elapsed) * 1_000_000_000 / u128::from(frequency().max(1))) as u64,
+        Clock::Hpet | Clock::Uptime => elapsed,
+    };
+    let ns = BASE_NS.load(Ordering::Relaxed) + elapsed_ns;
+    LAST_NS.fetch_max(ns, Ordering::Relaxed).max(ns)
+}
+
+// measures the time spent in a piece of code with the resolution of `now_ns`
+#[derive(Debug, Clone, Copy)]
+pub struct Stopwatch {
+    start: u64,
+}
+
+impl Stopwatch {
+    pub fn start() -> Self {
+        Stopwatch { start: now_ns() }
+    }
+
+    pub fn elapsed_ns(&self) -> u64 {
+        now_ns() - self.start
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_nanos(self.elapsed_ns())
+    }
+
+    // returns the elapsed time and starts again
+    pub fn restart(&mut self) -> Duration {
+        let now = now_ns();
+        let elapsed = now - self.start;
+        self.start = now;
+        Duration::from_nanos(elapsed)
+    }
+}
+
+#[test_case]
+fn timestamps_are_monotonic() {
+    let mut last = now_ns();
+    for _ in 0..10_000 {
+        let now = now_ns();
+        assert!(now >= last);
+        last = now;
+    }
+}
+
+#[test_case]
+fn stopwatch_agrees_with_the_pit() {
+    assert!(frequency() > 0);
+    let stopwatch = Stopwatch::start();
+    pit::busy_wait(Duration::from_millis(20));
+    let elapsed = stopwatch.elapsed();
+    // 5% for the emulated clocks
+    assert!(elapsed >= Duration::from_millis(19) && elapsed <= Duration::from_millis(21), "measured {:?}", elapsed);
+}
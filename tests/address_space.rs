@@ -0,0 +1,138 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use x86_64::VirtAddr;
+
+use rust_kernel::allocator;
+use rust_kernel::memory::{self, bitmap::BitmapFrameAllocator, vmm::{self, RegionKind, VmmError}};
+use rust_kernel::memory::address_space::{self, AddressSpace, USER_START, USER_END};
+use x86_64::structures::paging::PageTableFlags;
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo)->!{
+
+    rust_kernel::init();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe {memory::init(physical_memory_offset)};
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, physical_memory_offset)
+    };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+    test_main();
+    rust_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::test_panic_handler(info)
+}
+
+fn free_frames() -> usize {
+    memory::with_kernel_memory(|_, frame_allocator| frame_allocator.free_frames())
+}
+
+const USER_PAGE: u64 = USER_START + 0x40_0000;
+
+#[test_case]
+fn user_pages_are_private_to_the_address_space() {
+    let mut first = AddressSpace::new().expect("creating the address space failed");
+    let mut second = AddressSpace::new().expect("creating the address space failed");
+    let address = VirtAddr::new(USER_PAGE);
+    first.map_user(address, 4096, PageTableFlags::WRITABLE).unwrap();
+    second.map_user(address, 4096, PageTableFlags::WRITABLE).unwrap();
+    assert_ne!(first.translate(address), second.translate(address));
+    // the kernel address space doesn't see either of them
+    assert_eq!(memory::translate(address), None);
+
+    let word: *mut u64 = address.as_mut_ptr();
+    first.activate();
+    assert!(first.is_active());
+    unsafe {
+        assert_eq!(word.read_volatile(), 0);
+        word.write_volatile(1);
+    }
+    second.activate();
+    unsafe {
+        assert_eq!(word.read_volatile(), 0);
+        word.write_volatile(2);
+    }
+    first.activate();
+    assert_eq!(unsafe {word.read_volatile()}, 1);
+    address_space::activate_kernel();
+    assert!(!first.is_active() && !second.is_active());
+}
+
+#[test_case]
+fn kernel_stays_mapped_after_switching() {
+    let space = AddressSpace::new().expect("creating the address space failed");
+    let before = Box::new(41);
+    space.activate();
+    // heap, stack and the memory subsystem keep working
+    let during = Box::new(*before + 1);
+    let stack = memory::alloc_kernel_stack(1).expect("allocating a stack failed");
+    drop(stack);
+    address_space::activate_kernel();
+    assert_eq!(*during, 42);
+}
+
+#[test_case]
+fn copy_on_write_faults_use_the_active_address_space() {
+    let source = vmm::allocate("source", RegionKind::Task, 4096, PageTableFlags::WRITABLE).unwrap();
+    unsafe {source.start.as_mut_ptr::<u64>().write_volatile(3)};
+    let copy = vmm::share_copy_on_write(source.start, "copy").unwrap();
+    let space = AddressSpace::new().expect("creating the address space failed");
+    space.activate();
+
+    let faults = vmm::cow_faults();
+    unsafe {copy.start.as_mut_ptr::<u64>().write_volatile(7)};
+    assert_eq!(vmm::cow_faults(), faults + 1);
+    address_space::activate_kernel();
+
+    assert_eq!(unsafe {copy.start.as_ptr::<u64>().read_volatile()}, 7);
+    assert_eq!(unsafe {source.start.as_ptr::<u64>().read_volatile()}, 3);
+    vmm::release(copy.start).unwrap();
+    vmm::release(source.start).unwrap();
+}
+
+#[test_case]
+fn dropping_frees_all_frames() {
+    let free = free_frames();
+    let mut space = AddressSpace::new().expect("creating the address space failed");
+    // spread over several page tables
+    space.map_user(VirtAddr::new(USER_PAGE), 16 * 4096, PageTableFlags::WRITABLE).unwrap();
+    space.map_user(VirtAddr::new(USER_END - 4096), 4096, PageTableFlags::empty()).unwrap();
+    assert!(free_frames() < free - 17);
+    space.activate();
+    drop(space);
+    assert_eq!(free_frames(), free);
+}
+
+#[test_case]
+fn unmapping_frees_the_frames() {
+    let mut space = AddressSpace::new().expect("creating the address space failed");
+    let address = VirtAddr::new(USER_PAGE);
+    space.map_user(address, 4 * 4096, PageTableFlags::WRITABLE).unwrap();
+    let free = free_frames();
+    space.unmap_user(address, 4 * 4096).unwrap();
+    assert_eq!(free_frames(), free + 4);
+    assert_eq!(space.translate(address), None);
+}
+
+#[test_case]
+fn only_the_user_half_can_be_mapped() {
+    let mut space = AddressSpace::new().expect("creating the address space failed");
+    let kernel_address = VirtAddr::new(allocator::heap_start() as u64);
+    assert_eq!(space.map_user(kernel_address, 4096, PageTableFlags::WRITABLE), Err(VmmError::InvalidRange));
+    assert_eq!(space.map_user(VirtAddr::new(USER_END - 4096), 8192, PageTableFlags::WRITABLE), Err(VmmError::InvalidRange));
+    assert_eq!(space.map_user(VirtAddr::new(USER_PAGE), 0, PageTableFlags::WRITABLE), Err(VmmError::InvalidRange));
+}
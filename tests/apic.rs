@@ -0,0 +1,134 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use x86_64::VirtAddr;
+
+use rust_kernel::{acpi, allocator, time::{self, TimerSource}};
+use rust_kernel::interrupts::{self, apic::{self, ApicError}};
+use rust_kernel::memory::{self, bitmap::BitmapFrameAllocator};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo)->!{
+
+    rust_kernel::init();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe {memory::init(physical_memory_offset)};
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, physical_memory_offset)
+    };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+    let result = apic::init();
+    if cfg!(feature = "legacy-pic") {
+        assert_eq!(result, Err(ApicError::Disabled));
+    } else {
+        result.expect("switching to the apic failed");
+    }
+    test_main();
+    rust_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::test_panic_handler(info)
+}
+
+fn wait_for(irq: u8) {
+    let count = interrupts::irq_count(irq);
+    while interrupts::irq_count(irq) == count {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn madt_describes_the_qemu_machine() {
+    let madt = acpi::madt().expect("no MADT");
+    assert_eq!(madt.local_apic.as_u64(), 0xfee0_0000);
+    let io_apic = madt.io_apics().next().expect("no IO-APIC");
+    assert_eq!(io_apic.gsi_base, 0);
+    // the PIT is wired to GSI 2 on the PC, the firmware reports it as an override
+    assert_eq!(madt.isa_routes[0].gsi, 2);
+    assert_eq!(madt.isa_routes[1].gsi, 1);
+}
+
+#[test_case]
+fn timer_interrupts_arrive() {
+    assert_eq!(apic::is_enabled(), !cfg!(feature = "legacy-pic"));
+    let ticks = time::ticks();
+    wait_for(interrupts::timer_irq());
+    assert!(time::ticks() > ticks);
+}
+
+#[test_case]
+fn the_local_apic_timer_replaces_the_pit() {
+    if !apic::is_enabled() {
+        assert_eq!(time::timer_source(), TimerSource::Pit);
+        return;
+    }
+    assert!(apic::timer_frequency() > 0);
+    assert_eq!(time::timer_source(), TimerSource::LocalApic);
+    assert_eq!(interrupts::timer_irq(), interrupts::LAPIC_TIMER_IRQ);
+    // the period is rounded to whole ticks of the local APIC timer
+    assert!(time::tick_frequency().abs_diff(time::DEFAULT_TICK_FREQUENCY) <= 1);
+
+    // IRQ 0 is masked, only the local APIC timer ticks
+    let pit = interrupts::irq_count(interrupts::TIMER_IRQ);
+    let start = time::ticks();
+    while time::ticks() < start + 10 {
+        x86_64::instructions::hlt();
+    }
+    assert_eq!(interrupts::irq_count(interrupts::TIMER_IRQ), pit);
+}
+
+#[test_case]
+fn the_uptime_survives_switching_back_to_the_pit() {
+    if !apic::is_enabled() {
+        return;
+    }
+    let before = time::uptime();
+    time::set_timer_source(TimerSource::Pit).unwrap();
+    assert_eq!(interrupts::timer_irq(), interrupts::TIMER_IRQ);
+    assert!(time::uptime() >= before);
+    wait_for(interrupts::TIMER_IRQ);
+
+    time::set_timer_source(TimerSource::LocalApic).unwrap();
+    assert!(time::uptime() >= before);
+    wait_for(interrupts::LAPIC_TIMER_IRQ);
+}
+
+#[test_case]
+fn the_cascade_line_is_not_routed() {
+    if !apic::is_enabled() {
+        return;
+    }
+    // IRQ 0 took GSI 2 over
+    assert_eq!(apic::isa_route(2), None);
+    assert_eq!(apic::isa_route(0).map(|route| route.gsi), Some(2));
+}
+
+#[test_case]
+fn registered_handlers_run_through_the_apic() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+    static CALLED: AtomicBool = AtomicBool::new(false);
+    fn handler() {
+        CALLED.store(true, Ordering::Relaxed);
+    }
+
+    interrupts::register_irq(11, handler).unwrap();
+    let count = interrupts::irq_count(11);
+    // the EOI for a software interrupt is ignored by the local APIC as well
+    unsafe {core::arch::asm!("int {vector}", vector = const interrupts::PIC_2_OFFSET + 3)};
+    assert!(CALLED.load(Ordering::Relaxed));
+    assert_eq!(interrupts::irq_count(11), count + 1);
+    interrupts::unregister_irq(11).unwrap();
+    // the timer still gets its EOIs
+    wait_for(interrupts::timer_irq());
+}
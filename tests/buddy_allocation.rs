@@ -0,0 +1,133 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use x86_64::{PhysAddr, VirtAddr, structures::paging::PhysFrame};
+
+use rust_kernel::allocator;
+use rust_kernel::memory::{self, bitmap::BitmapFrameAllocator, buddy::{self, BuddyFrameAllocator, MAX_ORDER}};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo)->!{
+
+    rust_kernel::init();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe {memory::init(physical_memory_offset)};
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, physical_memory_offset)
+    };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+
+    // a single pool of the largest block size
+    buddy::init(buddy::POOL_FRAMES).expect("no contiguous pool for the buddy allocator");
+
+    test_main();
+    rust_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::test_panic_handler(info)
+}
+
+// after every test the whole pool has to be merged back into one block
+fn assert_fully_coalesced(buddy: &BuddyFrameAllocator) {
+    assert_eq!(buddy.free_frames(), buddy.total_frames());
+    assert_eq!(buddy.free_blocks(MAX_ORDER), 1);
+    for order in 0..MAX_ORDER {
+        assert_eq!(buddy.free_blocks(order), 0);
+    }
+}
+
+#[test_case]
+fn split_and_coalesce() {
+    buddy::with_buddy(|buddy| {
+        assert_fully_coalesced(buddy);
+
+        let frame = buddy.allocate(0).expect("out of frames");
+        // splitting the only block leaves one free buddy on every lower order
+        for order in 0..MAX_ORDER {
+            assert_eq!(buddy.free_blocks(order), 1);
+        }
+        assert_eq!(buddy.free_blocks(MAX_ORDER), 0);
+        assert_eq!(buddy.free_frames(), buddy.total_frames() - 1);
+
+        unsafe {buddy.deallocate(frame, 0)};
+        assert_fully_coalesced(buddy);
+    });
+}
+
+#[test_case]
+fn blocks_are_aligned_and_disjoint() {
+    use alloc::vec::Vec;
+    buddy::with_buddy(|buddy| {
+        let mut blocks = Vec::new();
+        for order in [3, 0, 5, 1, 0, 4, 2] {
+            let frame = buddy.allocate(order).expect("out of frames");
+            let start = frame.start_address().as_u64();
+            let size = 4096u64 << order;
+            assert_eq!(start % size, 0);
+            for &(other, other_size) in &blocks {
+                assert!(start + size <= other || other + other_size <= start);
+            }
+            blocks.push((start, size));
+        }
+        // free in a different order than allocated
+        while let Some((start, size)) = blocks.pop() {
+            let frame = PhysFrame::containing_address(PhysAddr::new(start));
+            unsafe {buddy.deallocate(frame, (size / 4096).trailing_zeros() as usize)};
+        }
+        assert_fully_coalesced(buddy);
+    });
+}
+
+#[test_case]
+fn fragmentation() {
+    use alloc::vec::Vec;
+    buddy::with_buddy(|buddy| {
+        let mut frames = Vec::new();
+        while let Some(frame) = buddy.allocate(0) {
+            frames.push(frame);
+        }
+        assert_eq!(frames.len(), 1 << MAX_ORDER);
+        assert_eq!(buddy.free_frames(), 0);
+        assert!(buddy.allocate(0).is_none());
+
+        // freeing every other frame leaves half the pool free but no two buddies free
+        for frame in frames.iter().step_by(2) {
+            unsafe {buddy.deallocate(*frame, 0)};
+        }
+        assert_eq!(buddy.free_frames(), frames.len() / 2);
+        assert_eq!(buddy.largest_free_order(), Some(0));
+        assert!(buddy.allocate(1).is_none());
+
+        for frame in frames.iter().skip(1).step_by(2) {
+            unsafe {buddy.deallocate(*frame, 0)};
+        }
+        assert_fully_coalesced(buddy);
+        let block = buddy.allocate(MAX_ORDER).expect("pool is still fragmented");
+        unsafe {buddy.deallocate(block, MAX_ORDER)};
+    });
+}
+
+#[test_case]
+fn free_lists_live_in_the_free_blocks() {
+    buddy::with_buddy(|buddy| {
+        let allocations = allocator::stats().allocations;
+        let frames = [0, 3, 1, 0].map(|order| (buddy.allocate(order).expect("out of frames"), order));
+        for (frame, order) in frames {
+            unsafe {buddy.deallocate(frame, order)};
+        }
+        // splitting and merging doesn't touch the heap
+        assert_eq!(allocator::stats().allocations, allocations);
+        assert_fully_coalesced(buddy);
+    });
+}
@@ -0,0 +1,61 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+use rust_kernel::{QemuExitCode, exit_qemu, serial_print, serial_println, hlt_loop};
+use rust_kernel::allocator;
+use rust_kernel::memory::{self, bitmap::BitmapFrameAllocator, buddy::BuddyFrameAllocator};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo)->!{
+    rust_kernel::init();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe {memory::init(physical_memory_offset)};
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, physical_memory_offset)
+    };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> !{
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    hlt_loop();
+}
+
+pub fn test_runner(tests: &[&dyn Fn()]) {
+    serial_println!("Running {} tests", tests.len());
+    for test in tests{
+        test();
+        serial_println!("[test did not panic]");
+        exit_qemu(QemuExitCode::Failed);
+    }
+    exit_qemu(QemuExitCode::Success);
+}
+
+#[test_case]
+fn double_free(){
+    serial_print!("buddy_double_free::double_free...\t");
+    let mut buddy = BuddyFrameAllocator::new();
+    let pool = memory::with_kernel_memory(|_, frame_allocator| {
+        frame_allocator.allocate_contiguous(16, 16)
+    }).expect("no contiguous pool for the buddy allocator");
+    unsafe {buddy.add_region(pool)};
+    let frame = buddy.allocate(2).expect("out of frames");
+    unsafe {
+        buddy.deallocate(frame, 2);
+        buddy.deallocate(frame, 2);
+    }
+}
@@ -0,0 +1,106 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use x86_64::VirtAddr;
+
+use rust_kernel::allocator;
+use rust_kernel::memory::{self, bitmap::BitmapFrameAllocator, vmm::{self, Region, RegionKind}};
+use x86_64::structures::paging::{PageTableFlags, PhysFrame, Translate};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo)->!{
+
+    rust_kernel::init();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe {memory::init(physical_memory_offset)};
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, physical_memory_offset)
+    };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+    test_main();
+    rust_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::test_panic_handler(info)
+}
+
+fn frame_of(address: VirtAddr) -> PhysFrame {
+    let physical = memory::with_kernel_memory(|mapper, _| mapper.translate_addr(address))
+        .expect("address is not mapped");
+    PhysFrame::containing_address(physical)
+}
+
+fn reference_count(address: VirtAddr) -> usize {
+    let frame = frame_of(address);
+    memory::with_kernel_memory(|_, frame_allocator| frame_allocator.reference_count(frame))
+}
+
+fn words(region: &Region) -> &'static mut [u64] {
+    unsafe {core::slice::from_raw_parts_mut(region.start.as_mut_ptr(), region.size as usize / 8)}
+}
+
+#[test_case]
+fn mappings_diverge_after_a_write() {
+    let source = vmm::allocate("source", RegionKind::Task, 2 * 4096, PageTableFlags::WRITABLE).unwrap();
+    for (i, word) in words(&source).iter_mut().enumerate() {
+        *word = i as u64;
+    }
+
+    let copy = vmm::share_copy_on_write(source.start, "copy").expect("sharing the region failed");
+    assert_eq!(frame_of(source.start), frame_of(copy.start));
+    assert_eq!(reference_count(source.start), 2);
+    assert_eq!(words(&source), words(&copy));
+
+    let faults = vmm::cow_faults();
+    words(&copy)[1] = 1000;
+    assert_eq!(vmm::cow_faults(), faults + 1);
+    assert_eq!(words(&copy)[1], 1000);
+    assert_eq!(words(&source)[1], 1);
+    // the rest of the page was copied
+    assert_eq!(words(&copy)[2], 2);
+    assert_ne!(frame_of(source.start), frame_of(copy.start));
+    assert_eq!(reference_count(source.start), 1);
+
+    // the second page is still shared
+    let second_page = source.start + 4096u64;
+    assert_eq!(frame_of(second_page), frame_of(copy.start + 4096u64));
+
+    // the source is the only mapping of its first frame now, so it is written in place
+    let frame = frame_of(source.start);
+    words(&source)[0] = 2000;
+    assert_eq!(vmm::cow_faults(), faults + 2);
+    assert_eq!(frame_of(source.start), frame);
+    assert_eq!(words(&copy)[0], 0);
+
+    vmm::release(copy.start).unwrap();
+    vmm::release(source.start).unwrap();
+}
+
+#[test_case]
+fn shared_frames_are_freed_with_the_last_mapping() {
+    let free = || memory::with_kernel_memory(|_, frame_allocator| frame_allocator.free_frames());
+    let source = vmm::allocate("source", RegionKind::Task, 4096, PageTableFlags::WRITABLE).unwrap();
+    let copy = vmm::share_copy_on_write(source.start, "copy").unwrap();
+    let frame = frame_of(source.start);
+
+    let before = free();
+    vmm::release(source.start).unwrap();
+    // the copy still uses the frame
+    assert_eq!(free(), before);
+    memory::with_kernel_memory(|_, frame_allocator| assert_eq!(frame_allocator.reference_count(frame), 1));
+
+    vmm::release(copy.start).unwrap();
+    assert_eq!(free(), before + 1);
+    memory::with_kernel_memory(|_, frame_allocator| assert_eq!(frame_allocator.reference_count(frame), 0));
+}
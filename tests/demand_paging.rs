@@ -0,0 +1,112 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use x86_64::VirtAddr;
+
+use rust_kernel::allocator;
+use rust_kernel::memory::{self, bitmap::BitmapFrameAllocator, vmm::{self, Backing, RegionKind}};
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::PageTableFlags;
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo)->!{
+
+    rust_kernel::init();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe {memory::init(physical_memory_offset)};
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, physical_memory_offset)
+    };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+    test_main();
+    rust_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::test_panic_handler(info)
+}
+
+fn free_frames() -> usize {
+    memory::with_kernel_memory(|_, frame_allocator| frame_allocator.free_frames())
+}
+
+#[test_case]
+fn lazy_region_is_mapped_on_first_touch() {
+    let free = free_frames();
+    let faults = vmm::lazy_faults();
+    // 64 MiB of address space, only the touched pages get frames
+    let region = vmm::reserve_lazy("lazy buffer", RegionKind::Task, 64 * 1024 * 1024, PageTableFlags::WRITABLE)
+        .expect("reserving the lazy region failed");
+    assert_eq!(region.backing, Backing::Lazy(PageTableFlags::WRITABLE));
+    assert_eq!(free_frames(), free);
+
+    let base: *mut u64 = region.start.as_mut_ptr();
+    let touched = [0, 1, 4096, 1024 * 1024];
+    for &index in &touched {
+        unsafe {
+            let word = base.add(index);
+            // fresh pages are zeroed
+            assert_eq!(word.read_volatile(), 0);
+            word.write_volatile(index as u64 + 1);
+        }
+    }
+    for &index in &touched {
+        assert_eq!(unsafe {base.add(index).read_volatile()}, index as u64 + 1);
+    }
+
+    // words 0 and 1 share a page, the page tables need a few frames as well
+    assert_eq!(vmm::lazy_faults(), faults + 3);
+    let used = free - free_frames();
+    assert!((3..16).contains(&used), "{used} frames used for 3 pages");
+
+    vmm::release(region.start).unwrap();
+    assert!(free - free_frames() < used);
+}
+
+#[test_case]
+fn resolved_faults_keep_the_registers() {
+    use rust_kernel::interrupts::exceptions;
+    let region = vmm::reserve_lazy("lazy registers", RegionKind::Task, 4096, PageTableFlags::WRITABLE)
+        .expect("reserving the lazy region failed");
+    let fatal_registers = exceptions::saved_registers().rax;
+    let (mut rax, mut rcx, mut r8, mut r15) = (0x1111u64, 0x2222u64, 0x3333u64, 0x4444u64);
+    unsafe {
+        core::arch::asm!(
+            "mov qword ptr [{page}], rax",
+            page = in(reg) region.start.as_u64(),
+            inout("rax") rax, inout("rcx") rcx, inout("r8") r8, inout("r15") r15,
+        );
+    }
+    assert_eq!((rax, rcx, r8, r15), (0x1111, 0x2222, 0x3333, 0x4444));
+    // only fatal exceptions record their registers
+    assert_eq!(exceptions::saved_registers().rax, fatal_registers);
+    assert_eq!(unsafe {region.start.as_ptr::<u64>().read_volatile()}, 0x1111);
+    vmm::release(region.start).unwrap();
+}
+
+#[test_case]
+fn faults_outside_lazy_regions_are_not_handled() {
+    let not_present = PageFaultErrorCode::CAUSED_BY_WRITE;
+    // not a region at all
+    assert!(!vmm::handle_page_fault(VirtAddr::new(0x_dead_0000_0000), not_present));
+
+    // a region that is only reserved
+    let region = vmm::reserve("reserved", RegionKind::Task, 4096).unwrap();
+    assert!(!vmm::handle_page_fault(region.start, not_present));
+    vmm::release(region.start).unwrap();
+
+    // a protection violation in a lazy region
+    let region = vmm::reserve_lazy("lazy", RegionKind::Task, 4096, PageTableFlags::empty()).unwrap();
+    assert!(!vmm::handle_page_fault(region.start, not_present | PageFaultErrorCode::PROTECTION_VIOLATION));
+    vmm::release(region.start).unwrap();
+}
@@ -0,0 +1,104 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator};
+
+use rust_kernel::memory::bitmap::BitmapFrameAllocator;
+use core::panic::PanicInfo;
+use spin::Mutex;
+
+static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo)->!{
+
+    rust_kernel::init();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, physical_memory_offset)
+    };
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+    test_main();
+    rust_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::test_panic_handler(info)
+}
+
+#[test_case]
+fn allocate_updates_counts() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let free = allocator.free_frames();
+    let used = allocator.used_frames();
+    assert!(free > 0);
+    assert_eq!(free + used, allocator.total_frames());
+
+    let frame = allocator.allocate_frame().expect("out of frames");
+    assert!(allocator.is_allocated(frame));
+    assert_eq!(allocator.free_frames(), free - 1);
+    assert_eq!(allocator.used_frames(), used + 1);
+
+    unsafe { allocator.deallocate_frame(frame) };
+    assert!(!allocator.is_allocated(frame));
+    assert_eq!(allocator.free_frames(), free);
+    assert_eq!(allocator.used_frames(), used);
+}
+
+#[test_case]
+fn frames_are_unique() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let mut frames = [None; 64];
+    for i in 0..frames.len() {
+        let frame = allocator.allocate_frame().expect("out of frames");
+        assert!(!frames.contains(&Some(frame)));
+        frames[i] = Some(frame);
+    }
+    for frame in frames.iter().flatten() {
+        unsafe { allocator.deallocate_frame(*frame) };
+    }
+}
+
+#[test_case]
+fn freed_frame_is_reused() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let first = allocator.allocate_frame().expect("out of frames");
+    let second = allocator.allocate_frame().expect("out of frames");
+    unsafe { allocator.deallocate_frame(first) };
+    let third = allocator.allocate_frame().expect("out of frames");
+    assert_eq!(first, third);
+    unsafe {
+        allocator.deallocate_frame(second);
+        allocator.deallocate_frame(third);
+    }
+}
+
+#[test_case]
+fn shared_frame_is_freed_with_last_reference() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let free = allocator.free_frames();
+    let frame = allocator.allocate_frame().expect("out of frames");
+    assert_eq!(allocator.reference_count(frame), 1);
+    allocator.add_reference(frame);
+    assert_eq!(allocator.reference_count(frame), 2);
+
+    unsafe { allocator.deallocate_frame(frame) };
+    assert!(allocator.is_allocated(frame));
+    assert_eq!(allocator.reference_count(frame), 1);
+    assert_eq!(allocator.free_frames(), free - 1);
+
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.reference_count(frame), 0);
+    assert_eq!(allocator.free_frames(), free);
+}
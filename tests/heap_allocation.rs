@@ -10,7 +10,7 @@ use bootloader::{entry_point, BootInfo};
 use x86_64::VirtAddr;
 
 use rust_kernel::allocator;
-use rust_kernel::memory::{self, BootInfoFrameAllocator};
+use rust_kernel::memory::{self, bitmap::BitmapFrameAllocator};
 use core::panic::PanicInfo;
 
 entry_point!(main);
@@ -19,11 +19,12 @@ fn main(boot_info: &'static BootInfo)->!{
 
     rust_kernel::init();
     let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe {memory::init(physical_memory_offset)};
-    let mut frame_allocator = unsafe {
-        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    let mapper = unsafe {memory::init(physical_memory_offset)};
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, physical_memory_offset)
     };
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
     test_main();
     rust_kernel::hlt_loop();
 }
@@ -64,11 +65,11 @@ fn many_boxes(){
     }
 }
 
+// the bump allocator can only reuse memory once every allocation is freed
+// so a single long lived allocation makes the heap grow for every box
+#[cfg(not(feature = "alloc-bump"))]
 #[test_case]
 fn many_boxes_long_lived(){
-    /// ---------------------------- ///
-    /// * fails for bump allocator * ///
-    /// ---------------------------- ///
     use rust_kernel::allocator::HEAP_SIZE;
     use alloc::boxed::Box;
     let long_lived = Box::new(67);
@@ -77,4 +78,60 @@ fn many_boxes_long_lived(){
         assert_eq!(*x, i);
     }
     assert_eq!(*long_lived, 67);
-}
\ No newline at end of file
+}
+
+#[test_case]
+fn multi_megabyte_allocation(){
+    use alloc::vec;
+    let size = 4 * 1024 * 1024;
+    let buffer = vec![0xAAu8; size];
+    assert!(buffer.iter().all(|&b| b == 0xAA));
+    assert!(allocator::heap_size() >= size);
+}
+
+#[test_case]
+fn heap_limit_is_respected(){
+    use alloc::vec::Vec;
+    let mut vec: Vec<u8> = Vec::new();
+    assert!(vec.try_reserve_exact(allocator::heap_limit()).is_err());
+}
+
+#[test_case]
+fn stats_track_allocations(){
+    use alloc::boxed::Box;
+    let before = allocator::stats();
+    let value = Box::new([0u8; 1000]);
+    let during = allocator::stats();
+    assert_eq!(during.bytes_allocated, before.bytes_allocated + 1000);
+    assert_eq!(during.allocations, before.allocations + 1);
+    assert!(during.peak_allocated >= during.bytes_allocated);
+    assert!(during.bytes_free + during.bytes_allocated <= during.heap_size);
+    if let Some(largest) = during.largest_free_block {
+        assert!(largest <= during.bytes_free);
+    }
+
+    drop(value);
+    let after = allocator::stats();
+    assert_eq!(after.bytes_allocated, before.bytes_allocated);
+    assert_eq!(after.allocations, before.allocations);
+    assert!(after.peak_allocated >= before.bytes_allocated + 1000);
+}
+
+#[cfg(feature = "alloc-fixed-block")]
+#[test_case]
+fn stats_report_size_classes(){
+    use alloc::boxed::Box;
+    // 64 byte blocks
+    let index = 3;
+    let before = allocator::stats().size_classes.unwrap()[index];
+    assert_eq!(before.block_size, 64);
+
+    let value = Box::new([0u8; 64]);
+    let during = allocator::stats().size_classes.unwrap()[index];
+    assert_eq!(during.allocated, before.allocated + 1);
+
+    drop(value);
+    let after = allocator::stats().size_classes.unwrap()[index];
+    assert_eq!(after.allocated, before.allocated);
+    assert_eq!(after.free, during.free + 1);
+}
@@ -0,0 +1,59 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+use rust_kernel::{QemuExitCode, exit_qemu, serial_print, serial_println, hlt_loop};
+use rust_kernel::allocator;
+use rust_kernel::memory::{self, bitmap::BitmapFrameAllocator};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo)->!{
+    rust_kernel::init();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe {memory::init(physical_memory_offset)};
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, physical_memory_offset)
+    };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> !{
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    hlt_loop();
+}
+
+pub fn test_runner(tests: &[&dyn Fn()]) {
+    serial_println!("Running {} tests", tests.len());
+    for test in tests{
+        test();
+        serial_println!("[test did not panic]");
+        exit_qemu(QemuExitCode::Failed);
+    }
+    exit_qemu(QemuExitCode::Success);
+}
+
+#[test_case]
+fn write_past_box(){
+    serial_print!("heap_overflow::write_past_box...\t");
+    let boxed = Box::new([0u8; 32]);
+    let ptr = Box::into_raw(boxed) as *mut u8;
+    unsafe {
+        // one byte behind the allocation, the free has to detect it
+        ptr.add(32).write_volatile(0);
+        drop(Box::from_raw(ptr as *mut [u8; 32]));
+    }
+}
@@ -0,0 +1,112 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use x86_64::VirtAddr;
+
+use rust_kernel::allocator;
+use rust_kernel::interrupts;
+use rust_kernel::memory::{self, bitmap::BitmapFrameAllocator};
+use rust_kernel::time::{self, hpet, tsc, Stopwatch, TimerSource};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo)->!{
+
+    rust_kernel::init();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe {memory::init(physical_memory_offset)};
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, physical_memory_offset)
+    };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+    time::init_hpet().expect("no HPET");
+    test_main();
+    rust_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::test_panic_handler(info)
+}
+
+#[test_case]
+fn the_counter_is_monotonic() {
+    assert!(hpet::frequency() > 0);
+    let mut last = hpet::now_ns();
+    for _ in 0..1000 {
+        let now = hpet::now_ns();
+        assert!(now >= last);
+        last = now;
+    }
+}
+
+#[test_case]
+fn the_tsc_is_calibrated_against_the_hpet() {
+    let stopwatch = Stopwatch::start();
+    hpet::busy_wait(Duration::from_millis(20)).unwrap();
+    let elapsed = stopwatch.elapsed();
+    assert!(elapsed >= Duration::from_millis(19) && elapsed <= Duration::from_millis(21), "measured {:?}", elapsed);
+}
+
+#[test_case]
+fn timestamps_fall_back_to_the_hpet() {
+    let expected = if tsc::is_invariant() { tsc::Clock::Tsc } else { tsc::Clock::Hpet };
+    assert_eq!(tsc::clock(), expected);
+}
+
+#[test_case]
+fn the_hpet_replaces_the_pit() {
+    let before = time::uptime();
+    time::set_timer_source(TimerSource::Hpet).unwrap();
+    assert_eq!(time::timer_source(), TimerSource::Hpet);
+    assert_eq!(time::tick_frequency(), time::DEFAULT_TICK_FREQUENCY);
+    assert!(time::uptime() >= before);
+
+    let start = time::ticks();
+    hpet::busy_wait(Duration::from_millis(50)).unwrap();
+    let elapsed = time::ticks() - start;
+    assert!(elapsed.abs_diff(50) <= 2, "{} ticks in 50 ms", elapsed);
+}
+
+#[test_case]
+fn one_shot_fires_once() {
+    static FIRED: AtomicU64 = AtomicU64::new(0);
+    fn handler() {
+        FIRED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    interrupts::register_irq(hpet::TIMER_1_IRQ, handler).unwrap();
+    let start = hpet::now_ns();
+    hpet::one_shot(hpet::TIMER_1, Duration::from_millis(5)).unwrap();
+    while FIRED.load(Ordering::Relaxed) == 0 {
+        x86_64::instructions::hlt();
+    }
+    assert!(hpet::now_ns() - start >= 5_000_000);
+    hpet::busy_wait(Duration::from_millis(10)).unwrap();
+    assert_eq!(FIRED.load(Ordering::Relaxed), 1);
+    hpet::stop(hpet::TIMER_1).unwrap();
+    interrupts::unregister_irq(hpet::TIMER_1_IRQ).unwrap();
+}
+
+#[test_case]
+fn the_pit_takes_over_again() {
+    let before = time::uptime();
+    time::set_timer_source(TimerSource::Pit).unwrap();
+    assert!(time::uptime() >= before);
+    // the HPET interrupts are routed elsewhere now
+    assert_eq!(hpet::one_shot(hpet::TIMER_1, Duration::from_millis(1)), Err(hpet::HpetError::InvalidTimer));
+    let ticks = time::ticks();
+    while time::ticks() < ticks + 2 {
+        x86_64::instructions::hlt();
+    }
+}
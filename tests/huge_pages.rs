@@ -0,0 +1,105 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use x86_64::{PhysAddr, VirtAddr};
+
+use rust_kernel::allocator;
+use rust_kernel::memory::{self, MappingSize, bitmap::BitmapFrameAllocator, vmm::{self, RegionKind, VmmError}};
+use x86_64::structures::paging::PageTableFlags;
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo)->!{
+
+    rust_kernel::init();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe {memory::init(physical_memory_offset)};
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, physical_memory_offset)
+    };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+    test_main();
+    rust_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::test_panic_handler(info)
+}
+
+const HUGE_PAGE: u64 = 2 * 1024 * 1024;
+
+fn free_frames() -> usize {
+    memory::with_kernel_memory(|_, frame_allocator| frame_allocator.free_frames())
+}
+
+#[test_case]
+fn large_regions_use_2mib_pages() {
+    let free = free_frames();
+    let region = vmm::allocate("huge buffer", RegionKind::Task, 2 * HUGE_PAGE, PageTableFlags::WRITABLE)
+        .expect("allocating the region failed");
+    assert!(region.start.is_aligned(HUGE_PAGE));
+
+    for offset in [0, HUGE_PAGE - 8, HUGE_PAGE, 2 * HUGE_PAGE - 8] {
+        let translation = memory::translate(region.start + offset).expect("region is not mapped");
+        assert_eq!(translation.page_size, MappingSize::Size2MiB);
+        assert!(translation.flags.contains(PageTableFlags::WRITABLE | PageTableFlags::HUGE_PAGE));
+        assert_eq!(translation.physical.as_u64() % HUGE_PAGE, offset % HUGE_PAGE);
+
+        let word: *mut u64 = (region.start + offset).as_mut_ptr();
+        unsafe {
+            word.write_volatile(offset);
+            assert_eq!(word.read_volatile(), offset);
+        }
+    }
+
+    vmm::release(region.start).unwrap();
+    assert_eq!(memory::translate(region.start), None);
+    // only page tables may be left over
+    assert!(free - free_frames() < 4);
+}
+
+#[test_case]
+fn small_regions_use_4kib_pages() {
+    let region = vmm::allocate("small buffer", RegionKind::Task, HUGE_PAGE - 4096, PageTableFlags::WRITABLE)
+        .expect("allocating the region failed");
+    let translation = memory::translate(region.start).expect("region is not mapped");
+    assert_eq!(translation.page_size, MappingSize::Size4KiB);
+    vmm::release(region.start).unwrap();
+}
+
+#[test_case]
+fn unaligned_physical_memory_falls_back_to_4kib_pages() {
+    // 2 MiB of physical memory starting one page after a 2 MiB boundary
+    let physical = PhysAddr::new(HUGE_PAGE + 4096);
+    let region = vmm::map_physical("unaligned", RegionKind::Mmio, physical, HUGE_PAGE, PageTableFlags::empty())
+        .expect("mapping the physical memory failed");
+    let translation = memory::translate(region.start).expect("region is not mapped");
+    assert_eq!(translation.page_size, MappingSize::Size4KiB);
+    assert_eq!(translation.physical, physical);
+    vmm::release(region.start).unwrap();
+
+    let aligned = PhysAddr::new(HUGE_PAGE);
+    let region = vmm::map_physical("aligned", RegionKind::Mmio, aligned, HUGE_PAGE, PageTableFlags::empty())
+        .expect("mapping the physical memory failed");
+    let translation = memory::translate(region.start + 4096u64).expect("region is not mapped");
+    assert_eq!(translation.page_size, MappingSize::Size2MiB);
+    assert_eq!(translation.physical, aligned + 4096u64);
+    vmm::release(region.start).unwrap();
+}
+
+#[test_case]
+fn huge_pages_are_not_shared_copy_on_write() {
+    let region = vmm::allocate("huge buffer", RegionKind::Task, HUGE_PAGE, PageTableFlags::WRITABLE)
+        .expect("allocating the region failed");
+    assert_eq!(vmm::share_copy_on_write(region.start, "copy"), Err(VmmError::HugePage));
+    vmm::release(region.start).unwrap();
+}
@@ -0,0 +1,75 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::arch::asm;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use bootloader::{entry_point, BootInfo};
+use rust_kernel::{QemuExitCode, exit_qemu, serial_print, serial_println, hlt_loop};
+use rust_kernel::interrupts::exceptions;
+
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo)->!{
+    rust_kernel::init();
+    test_main();
+    hlt_loop();
+}
+
+// the panic message of the fatal exception path, without an allocator
+struct Message {
+    bytes: [u8; 64],
+    len: usize,
+}
+
+impl Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !{
+    let mut message = Message { bytes: [0; 64], len: 0 };
+    let _ = write!(message, "{}", info.message());
+    let registers = exceptions::saved_registers();
+    if &message.bytes[..message.len] == b"EXCEPTION: INVALID OPCODE"
+        && registers.rax == 0x_1111_2222_3333_4444
+        && registers.r15 == 0x_5555_6666_7777_8888 {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        serial_println!("unexpected panic: {}", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    hlt_loop();
+}
+
+pub fn test_runner(tests: &[&dyn Fn()]) {
+    serial_println!("Running {} tests", tests.len());
+    for test in tests{
+        test();
+        serial_println!("[test did not panic]");
+        exit_qemu(QemuExitCode::Failed);
+    }
+    exit_qemu(QemuExitCode::Success);
+}
+
+#[test_case]
+fn invalid_opcode_is_fatal_with_registers(){
+    serial_print!("invalid_opcode::invalid_opcode_is_fatal_with_registers...\t");
+    unsafe {
+        asm!(
+            "ud2",
+            in("rax") 0x_1111_2222_3333_4444u64,
+            in("r15") 0x_5555_6666_7777_8888u64,
+        );
+    }
+}
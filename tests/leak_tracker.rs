@@ -0,0 +1,89 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use x86_64::VirtAddr;
+
+use rust_kernel::allocator::{self, leak::{self, Leaks}};
+use rust_kernel::memory::{self, bitmap::BitmapFrameAllocator};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo)->!{
+
+    rust_kernel::init();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe {memory::init(physical_memory_offset)};
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, physical_memory_offset)
+    };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+    test_main();
+    rust_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::test_panic_handler(info)
+}
+
+#[test_case]
+fn freed_allocations_are_no_leaks() {
+    use alloc::{boxed::Box, vec::Vec};
+    let checkpoint = allocator::leak_checkpoint();
+    let boxed = Box::new(41);
+    let mut vec = Vec::new();
+    for i in 0..100 {
+        vec.push(i);
+    }
+    drop(boxed);
+    drop(vec);
+    assert_eq!(allocator::leaks_since(checkpoint), Leaks { allocations: 0, bytes: 0 });
+}
+
+#[test_case]
+fn leaked_box_is_reported() {
+    use alloc::boxed::Box;
+    let checkpoint = allocator::leak_checkpoint();
+    let leaked = Box::leak(Box::new([0u64; 4]));
+    assert_eq!(allocator::leaks_since(checkpoint), Leaks { allocations: 1, bytes: 32 });
+    allocator::dump_leaks_since(checkpoint);
+    unsafe {drop(Box::from_raw(leaked))};
+    assert_eq!(allocator::leaks_since(checkpoint).allocations, 0);
+}
+
+#[test_case]
+fn call_site_has_return_addresses() {
+    assert!(leak::call_site().return_addresses().count() > 0);
+}
+
+#[test_case]
+fn finished_task_leaves_nothing_behind() {
+    use alloc::vec::Vec;
+    use rust_kernel::task::{Task, executor::Executor};
+
+    async fn sum() -> u64 {
+        let vec: Vec<u64> = (0..100).collect();
+        vec.iter().sum()
+    }
+    async fn check_sum() {
+        assert_eq!(sum().await, 4950);
+    }
+
+    let mut executor = Executor::new();
+    // the first task allocates the nodes of the executor maps, they are kept for later tasks
+    executor.spawn(Task::new(check_sum()));
+    executor.run_ready_tasks();
+
+    let checkpoint = allocator::leak_checkpoint();
+    executor.spawn(Task::new(check_sum()));
+    executor.run_ready_tasks();
+    assert_eq!(allocator::leaks_since(checkpoint).allocations, 0);
+}
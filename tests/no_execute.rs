@@ -0,0 +1,81 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use x86_64::VirtAddr;
+
+use rust_kernel::allocator;
+use rust_kernel::memory::{self, bitmap::BitmapFrameAllocator, protection, vmm::{self, RegionKind}};
+use x86_64::structures::paging::PageTableFlags;
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo)->!{
+
+    rust_kernel::init();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe {memory::init(physical_memory_offset)};
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, physical_memory_offset)
+    };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+    test_main();
+    rust_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::test_panic_handler(info)
+}
+
+fn flags(address: VirtAddr) -> PageTableFlags {
+    memory::translate(address).expect("address is not mapped").flags
+}
+
+#[test_case]
+fn no_execute_is_enabled() {
+    assert!(protection::no_execute_supported());
+    assert!(protection::no_execute_enabled());
+}
+
+#[test_case]
+fn heap_and_stacks_are_not_executable() {
+    assert!(flags(VirtAddr::new(allocator::heap_start() as u64)).contains(PageTableFlags::NO_EXECUTE));
+    let stack = memory::alloc_kernel_stack(2).expect("allocating the stack failed");
+    assert!(flags(stack.bottom()).contains(PageTableFlags::NO_EXECUTE));
+    assert!(flags(memory::physical_memory_offset()).contains(PageTableFlags::NO_EXECUTE));
+}
+
+#[test_case]
+fn writable_mappings_are_never_executable() {
+    let writable = vmm::allocate("writable", RegionKind::Task, 4096, PageTableFlags::WRITABLE).unwrap();
+    assert!(flags(writable.start).contains(PageTableFlags::NO_EXECUTE));
+    // read only mappings keep their execute rights
+    let read_only = vmm::allocate("read only", RegionKind::Task, 4096, PageTableFlags::empty()).unwrap();
+    assert!(!flags(read_only.start).contains(PageTableFlags::NO_EXECUTE));
+
+    let lazy = vmm::reserve_lazy("lazy", RegionKind::Task, 4096, PageTableFlags::WRITABLE).unwrap();
+    unsafe {lazy.start.as_mut_ptr::<u8>().write_volatile(1)};
+    assert!(flags(lazy.start).contains(PageTableFlags::NO_EXECUTE));
+
+    for region in [writable, read_only, lazy] {
+        vmm::release(region.start).unwrap();
+    }
+}
+
+#[test_case]
+fn new_mappings_pass_the_audit() {
+    let before = protection::audit();
+    let region = vmm::allocate("audited", RegionKind::Task, 8 * 4096, PageTableFlags::WRITABLE).unwrap();
+    let stack = memory::alloc_kernel_stack(1).unwrap();
+    assert_eq!(protection::audit(), before);
+    drop(stack);
+    vmm::release(region.start).unwrap();
+}
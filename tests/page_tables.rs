@@ -0,0 +1,91 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use x86_64::VirtAddr;
+
+use rust_kernel::allocator;
+use rust_kernel::memory::{self, bitmap::BitmapFrameAllocator, page_tables, vmm::{self, RegionKind}};
+use x86_64::structures::paging::PageTableFlags;
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo)->!{
+
+    rust_kernel::init();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe {memory::init(physical_memory_offset)};
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, physical_memory_offset)
+    };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+    test_main();
+    rust_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::test_panic_handler(info)
+}
+
+#[test_case]
+fn walk_agrees_with_translate() {
+    let address = VirtAddr::new(allocator::heap_start() as u64 + 0x123);
+    let walk = page_tables::walk(address);
+    let translation = memory::translate(address).expect("heap is not mapped");
+    assert_eq!(walk.physical, Some(translation.physical));
+    assert_eq!(walk.page_size, translation.page_size.bytes());
+
+    let levels = walk.steps.iter().flatten().map(|step| step.level);
+    assert!(levels.eq((1..=4).rev().take(walk.steps.iter().flatten().count())));
+    let last = walk.steps.iter().flatten().last().unwrap();
+    assert!(last.flags.contains(PageTableFlags::PRESENT | PageTableFlags::WRITABLE));
+}
+
+#[test_case]
+fn walk_stops_at_missing_entries() {
+    let stack = memory::alloc_kernel_stack(1).expect("allocating the stack failed");
+    let walk = page_tables::walk(stack.guard_page().start_address());
+    assert_eq!(walk.physical, None);
+    let last = walk.steps.iter().flatten().last().unwrap();
+    assert!(!last.flags.contains(PageTableFlags::PRESENT));
+}
+
+#[test_case]
+fn mappings_are_sorted_and_merged() {
+    let region = vmm::allocate("dump test", RegionKind::Task, 16 * 4096, PageTableFlags::WRITABLE)
+        .expect("allocating the region failed");
+
+    let mut previous: Option<page_tables::MappedRange> = None;
+    let mut covered = 0;
+    page_tables::for_each_mapping(|range| {
+        if let Some(previous) = previous {
+            assert!(previous.last() < range.start, "ranges overlap or are out of order");
+            // neighbours would have been merged
+            let adjacent = previous.last().as_u64() + 1 == range.start.as_u64()
+                && previous.physical + previous.size == range.physical;
+            assert!(!adjacent || previous.flags != range.flags);
+        }
+        // the region might be merged with a neighbour
+        let start = range.start.as_u64().max(region.start.as_u64());
+        let end = (range.last().as_u64() + 1).min(region.end().as_u64());
+        if start < end {
+            assert!(range.flags.contains(PageTableFlags::PRESENT | PageTableFlags::WRITABLE));
+            covered += end - start;
+        }
+        previous = Some(range);
+    });
+    assert_eq!(covered, region.size);
+
+    vmm::release(region.start).unwrap();
+    let mut mapped = false;
+    page_tables::for_each_mapping(|range| mapped |= range.contains(region.start));
+    assert!(!mapped);
+}
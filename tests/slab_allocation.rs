@@ -0,0 +1,139 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use x86_64::VirtAddr;
+
+use rust_kernel::allocator::{self, slab::{self, ObjectCache}};
+use rust_kernel::memory::{self, bitmap::BitmapFrameAllocator};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo)->!{
+
+    rust_kernel::init();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe {memory::init(physical_memory_offset)};
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, physical_memory_offset)
+    };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+    test_main();
+    rust_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::test_panic_handler(info)
+}
+
+struct Node {
+    value: u64,
+    next: Option<u64>,
+}
+
+fn new_node() -> Node {
+    Node { value: 67, next: None }
+}
+
+static NODES: ObjectCache<Node> = ObjectCache::new("node", new_node);
+static BUFFERS: ObjectCache<[u8; 200]> = ObjectCache::new("buffer", || [0xAA; 200]);
+static VALUES: ObjectCache<u64> = ObjectCache::without_constructor("value");
+
+#[test_case]
+fn objects_are_constructed() {
+    let mut node = NODES.alloc().expect("out of memory");
+    assert_eq!(node.value, 67);
+    assert!(node.next.is_none());
+    node.value = 41;
+    node.next = Some(1);
+    drop(node);
+
+    // objects are constructed again when they are reused
+    let node = NODES.alloc().expect("out of memory");
+    assert_eq!(node.value, 67);
+}
+
+#[test_case]
+fn caches_without_constructor_only_take_values() {
+    assert!(VALUES.alloc().is_none());
+    let value = VALUES.alloc_with(67).expect("out of memory");
+    assert_eq!(*value, 67);
+}
+
+#[test_case]
+fn empty_slabs_are_given_back() {
+    use alloc::vec::Vec;
+    // the first allocation registers the cache, which allocates on the heap
+    drop(BUFFERS.alloc());
+    let heap_before = allocator::stats().bytes_allocated;
+
+    let per_slab = BUFFERS.stats().objects_per_slab;
+    let buffers: Vec<_> = (0..per_slab * 3 + 1)
+                            .map(|_| BUFFERS.alloc().expect("out of memory"))
+                            .collect();
+    let stats = BUFFERS.stats();
+    assert_eq!(stats.slabs, 4);
+    assert_eq!(stats.objects_in_use, per_slab * 3 + 1);
+    assert_eq!(stats.objects_free, per_slab - 1);
+    assert!(buffers.iter().all(|buffer| buffer.iter().all(|&b| b == 0xAA)));
+
+    drop(buffers);
+    let stats = BUFFERS.stats();
+    assert_eq!(stats.slabs, 0);
+    assert_eq!(stats.objects_in_use, 0);
+    assert_eq!(allocator::stats().bytes_allocated, heap_before);
+}
+
+#[test_case]
+fn objects_are_disjoint() {
+    use alloc::vec::Vec;
+    let mut nodes: Vec<_> = (0..100).map(|_| NODES.alloc().expect("out of memory")).collect();
+    for (i, node) in nodes.iter_mut().enumerate() {
+        node.value = i as u64;
+    }
+    for (i, node) in nodes.iter().enumerate() {
+        assert_eq!(node.value, i as u64);
+    }
+}
+
+#[test_case]
+fn stats_list_used_caches() {
+    let node = NODES.alloc().expect("out of memory");
+    let stats = slab::stats();
+    let nodes = stats.iter().find(|cache| cache.name == "node").expect("node cache is not listed");
+    assert!(nodes.objects_in_use >= 1);
+    drop(node);
+}
+
+#[test_case]
+fn heap_stats_include_the_slab_caches() {
+    use alloc::format;
+    let node = NODES.alloc().expect("out of memory");
+    let stats = allocator::stats();
+    let nodes = stats.slab_caches().find(|cache| cache.name == "node").expect("node cache is not listed");
+    assert!(nodes.objects_in_use >= 1);
+    assert!(format!("{stats}").contains("slab node:"));
+    drop(node);
+}
+
+#[test_case]
+fn spawned_tasks_come_from_the_task_cache() {
+    use rust_kernel::task::{executor::Executor, Task};
+    let in_use = || {
+        slab::stats().iter().find(|cache| cache.name == "task").map_or(0, |cache| cache.objects_in_use)
+    };
+    let before = in_use();
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {}));
+    assert_eq!(in_use(), before + 1);
+    executor.run_ready_tasks();
+    assert_eq!(in_use(), before);
+}
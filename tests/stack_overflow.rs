@@ -2,10 +2,17 @@
 #![no_main]
 #![feature(abi_x86_interrupt)]
 
+use core::arch::asm;
 use core::panic::PanicInfo;
-use rust_kernel::{serial_print,exit_qemu, QemuExitCode, hlt_loop};
+use bootloader::{entry_point, BootInfo};
+use rust_kernel::{serial_print, serial_println, exit_qemu, QemuExitCode, hlt_loop};
+use rust_kernel::memory::{self, bitmap::BitmapFrameAllocator, KernelStack};
 use lazy_static::lazy_static;
+use spin::Once;
+use x86_64::VirtAddr;
+use x86_64::registers::control::Cr2;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::structures::paging::Page;
 
 
 #[panic_handler]
@@ -19,6 +26,9 @@ fn stack_overflow(){
     volatile::Volatile::new(0).read();
 }
 
+// the stack that is overflowed, its guard page has to be hit
+static TEST_STACK: Once<KernelStack> = Once::new();
+
 lazy_static! { 
     static ref TEST_IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
@@ -39,17 +49,49 @@ pub fn init_test_idt(){
 extern "x86-interrupt" fn test_double_fault_handler(
     _stack_frame: InterruptStackFrame,
     _error_code: u64) -> ! {
-    serial_print!("[ok]\n");
-    exit_qemu(QemuExitCode::Success);
+    // the page fault that caused the double fault was at the guard page of the overflowed stack
+    let fault_page = Page::containing_address(Cr2::read());
+    let guard_page = TEST_STACK.r#try().map(|stack| stack.guard_page());
+    if guard_page == Some(fault_page) {
+        serial_print!("[ok]\n");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\nfault at {:?}, guard page {:?}", fault_page, guard_page);
+        exit_qemu(QemuExitCode::Failed);
+    }
     hlt_loop();
 }
 
 
-#[no_mangle]
-pub extern "C" fn _start() -> !{
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> !{
     serial_print!("stack_overflow::stack_overflow...\t");
     rust_kernel::gdt::init();
     init_test_idt();
+
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe {memory::init(physical_memory_offset)};
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, physical_memory_offset)
+    };
+    // moves the double fault handler to a guarded stack as well
+    memory::init_kernel_memory(mapper, frame_allocator);
+    assert!(rust_kernel::gdt::double_fault_stack().is_some());
+
+    let stack = TEST_STACK.call_once(|| memory::alloc_kernel_stack(4).expect("allocating the test stack failed"));
+    unsafe {
+        asm!(
+            "mov rsp, {top}",
+            "call {entry}",
+            top = in(reg) stack.top().as_u64(),
+            entry = sym overflow_on_test_stack,
+            options(noreturn)
+        );
+    }
+}
+
+extern "C" fn overflow_on_test_stack() -> ! {
     stack_overflow();
     panic!("Executed continued after stack overflow");
-}
\ No newline at end of file
+}
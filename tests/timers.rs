@@ -0,0 +1,141 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use x86_64::VirtAddr;
+
+use rust_kernel::allocator;
+use rust_kernel::memory::{self, bitmap::BitmapFrameAllocator};
+use rust_kernel::task::{self, Task, executor::Executor, timer};
+use rust_kernel::time;
+use alloc::{rc::Rc, vec::Vec};
+use core::cell::{Cell, RefCell};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::time::Duration;
+use futures_util::stream::StreamExt;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo)->!{
+
+    rust_kernel::init();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe {memory::init(physical_memory_offset)};
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, physical_memory_offset)
+    };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+    test_main();
+    rust_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::test_panic_handler(info)
+}
+
+fn tick() -> Duration {
+    Duration::from_nanos(1_000_000_000 / u64::from(time::tick_frequency()))
+}
+
+// runs the future on an executor of its own, halting between ticks like `Executor::run`
+fn block_on<T: 'static>(future: impl Future<Output = T> + 'static) -> T {
+    let output = Rc::new(RefCell::new(None));
+    let result = output.clone();
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async move {
+        *result.borrow_mut() = Some(future.await);
+    }));
+    loop {
+        executor.run_ready_tasks();
+        if let Some(output) = output.borrow_mut().take() {
+            return output;
+        }
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn sleep_is_accurate_to_one_tick() {
+    for millis in [1, 5, 20, 50] {
+        let duration = Duration::from_millis(millis);
+        let elapsed = block_on(async move {
+            let start = time::uptime();
+            task::sleep(duration).await;
+            time::uptime() - start
+        });
+        assert!(elapsed >= duration, "slept {:?} instead of {:?}", elapsed, duration);
+        assert!(elapsed <= duration + tick(), "slept {:?} instead of {:?}", elapsed, duration);
+    }
+    assert_eq!(timer::pending_timers(), 0);
+}
+
+#[test_case]
+fn sleepers_wake_in_deadline_order() {
+    let order = Rc::new(RefCell::new(Vec::new()));
+    let done = Rc::new(Cell::new(0));
+    let mut executor = Executor::new();
+    for millis in [30u64, 10, 40, 20, 10] {
+        let (order, done) = (order.clone(), done.clone());
+        executor.spawn(Task::new(async move {
+            task::sleep(Duration::from_millis(millis)).await;
+            order.borrow_mut().push(millis);
+            done.set(done.get() + 1);
+        }));
+    }
+    while done.get() < 5 {
+        executor.run_ready_tasks();
+        x86_64::instructions::hlt();
+    }
+    assert_eq!(*order.borrow(), [10, 10, 20, 30, 40]);
+}
+
+#[test_case]
+fn timeout_elapses() {
+    let (result, elapsed) = block_on(async {
+        let start = time::uptime();
+        let result = task::timeout(Duration::from_millis(10), task::sleep(Duration::from_secs(1))).await;
+        (result, time::uptime() - start)
+    });
+    assert_eq!(result, Err(task::Elapsed));
+    assert!(elapsed >= Duration::from_millis(10) && elapsed <= Duration::from_millis(10) + tick());
+    // the inner sleep was dropped with the timeout
+    assert_eq!(timer::pending_timers(), 0);
+}
+
+#[test_case]
+fn timeout_passes_the_output_through() {
+    let result = block_on(async {
+        task::timeout(Duration::from_millis(100), async {
+            task::sleep(Duration::from_millis(5)).await;
+            42
+        }).await
+    });
+    assert_eq!(result, Ok(42));
+    assert_eq!(timer::pending_timers(), 0);
+}
+
+#[test_case]
+fn interval_does_not_drift() {
+    let period = Duration::from_millis(10);
+    let (scheduled, elapsed) = block_on(async move {
+        let start = time::uptime();
+        let mut interval = task::interval(period);
+        let mut scheduled = Vec::new();
+        for _ in 0..5 {
+            scheduled.push(interval.next().await.unwrap());
+        }
+        (scheduled, time::uptime() - start)
+    });
+    for pair in scheduled.windows(2) {
+        assert_eq!(pair[1] - pair[0], period);
+    }
+    assert!(elapsed >= 5 * period && elapsed <= 5 * period + tick());
+}
@@ -0,0 +1,117 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use x86_64::{PhysAddr, VirtAddr};
+
+use rust_kernel::allocator;
+use rust_kernel::memory::{self, bitmap::BitmapFrameAllocator, vmm::{self, Backing, RegionKind, VmmError}};
+use x86_64::structures::paging::PageTableFlags;
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo)->!{
+
+    rust_kernel::init();
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe {memory::init(physical_memory_offset)};
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, physical_memory_offset)
+    };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+    test_main();
+    rust_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::test_panic_handler(info)
+}
+
+fn free_frames() -> usize {
+    memory::with_kernel_memory(|_, frame_allocator| frame_allocator.free_frames())
+}
+
+#[test_case]
+fn boot_regions_are_listed() {
+    let regions = vmm::regions();
+    assert!(regions.iter().any(|r| r.kind == RegionKind::PhysicalMemory));
+    let heap = vmm::find(VirtAddr::new(allocator::heap_start() as u64)).expect("heap is not a region");
+    assert_eq!(heap.kind, RegionKind::Heap);
+    vmm::dump_layout();
+}
+
+#[test_case]
+fn allocated_region_is_usable_and_released() {
+    let free = free_frames();
+    let region = vmm::allocate("test", RegionKind::Task, 3 * 4096, PageTableFlags::WRITABLE)
+        .expect("allocating a region failed");
+    assert_eq!(region.backing, Backing::Allocated);
+    assert_eq!(region.size, 3 * 4096);
+    assert!(free_frames() <= free - 3);
+
+    let words: &mut [u64] = unsafe {
+        core::slice::from_raw_parts_mut(region.start.as_mut_ptr(), region.size as usize / 8)
+    };
+    for (i, word) in words.iter_mut().enumerate() {
+        *word = i as u64;
+    }
+    assert!(words.iter().enumerate().all(|(i, &word)| word == i as u64));
+    assert_eq!(vmm::find(region.start + 4096u64), Some(region));
+
+    let released = vmm::release(region.start).expect("releasing the region failed");
+    assert_eq!(released, region);
+    assert_eq!(vmm::find(region.start), None);
+    // the page table frames for the region stay allocated
+    assert!(free_frames() >= free - 3);
+}
+
+#[test_case]
+fn released_space_is_reused() {
+    let first = vmm::reserve("first", RegionKind::Task, 4096).unwrap();
+    let second = vmm::reserve("second", RegionKind::Task, 4096).unwrap();
+    assert_eq!(second.start, first.end());
+    vmm::release(first.start).unwrap();
+    let third = vmm::reserve("third", RegionKind::Task, 4096).unwrap();
+    assert_eq!(third.start, first.start);
+    vmm::release(second.start).unwrap();
+    vmm::release(third.start).unwrap();
+}
+
+#[test_case]
+fn overlapping_reservations_fail() {
+    let heap = VirtAddr::new(allocator::heap_start() as u64);
+    assert!(matches!(
+        vmm::reserve_at("overlap", RegionKind::Task, heap, 4096),
+        Err(VmmError::Overlap)
+    ));
+    assert!(matches!(vmm::release(heap + 4096u64), Err(VmmError::NotFound)));
+}
+
+#[test_case]
+fn physical_mapping_shows_the_same_memory() {
+    // the vga text buffer, also reachable through the physical memory mapping
+    let region = vmm::map_physical("vga", RegionKind::Mmio, PhysAddr::new(0xb8000), 4096, PageTableFlags::WRITABLE)
+        .expect("mapping the vga buffer failed");
+    let physical_memory = vmm::regions().iter()
+        .find(|r| r.kind == RegionKind::PhysicalMemory)
+        .unwrap();
+    let through_region: *mut u16 = region.start.as_mut_ptr();
+    let through_offset: *mut u16 = (physical_memory.start + 0xb8000u64).as_mut_ptr();
+    unsafe {
+        through_region.write_volatile(0x0f41);
+        assert_eq!(through_offset.read_volatile(), 0x0f41);
+    }
+
+    let free = free_frames();
+    vmm::release(region.start).unwrap();
+    // the frames of a physical mapping are not given to the frame allocator
+    assert_eq!(free_frames(), free);
+}